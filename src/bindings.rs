@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use syn::visit::Visit;
+use syn::{Pat, PatIdent, Type};
+
+/// The local bindings in scope at the current point of the visit:
+/// function and closure parameters, `let`s, patterns of `match`/`if let`/`for`,
+/// and the functions defined inside blocks.
+///
+/// A call to a single-segment path whose name is one of these does not refer
+/// to the function a rule is about. Where the type of a binding is visible
+/// (`x: Foo`, `let x = Foo::new()`), it is kept as a hint for the method rules.
+#[derive(Default)]
+pub struct LocalBindings {
+    // name => the last segment of its type, if known
+    scopes: Vec<HashMap<String, Option<String>>>,
+}
+
+impl LocalBindings {
+    pub fn new() -> Self {
+        LocalBindings { scopes: vec![] }
+    }
+
+    pub fn push(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn pop(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Leave the bindings of the enclosing function behind, when entering a nested item
+    pub fn enter_item(&mut self) -> Vec<HashMap<String, Option<String>>> {
+        std::mem::take(&mut self.scopes)
+    }
+
+    pub fn leave_item(&mut self, saved: Vec<HashMap<String, Option<String>>>) {
+        self.scopes = saved;
+    }
+
+    pub fn add(&mut self, name: String, type_hint: Option<String>) {
+        if self.scopes.is_empty() {
+            self.push();
+        }
+        self.scopes.last_mut().unwrap().insert(name, type_hint);
+    }
+
+    /// Add all the names bound by a pattern. `type_hint` is the type of the value
+    /// it is matched against, if known
+    pub fn add_pat(&mut self, pat: &Pat, type_hint: Option<String>) {
+        match pat {
+            Pat::Type(pat_type) => {
+                let type_hint = type_name(&pat_type.ty).or(type_hint);
+                self.add_pat(&pat_type.pat, type_hint);
+            }
+            Pat::Ident(pat_ident) if pat_ident.subpat.is_none() => {
+                self.add(pat_ident.ident.to_string(), type_hint);
+            }
+            _ => {
+                let mut collector = PatBindings { names: vec![] };
+                collector.visit_pat(pat);
+                for name in collector.names {
+                    self.add(name, None);
+                }
+            }
+        }
+    }
+
+    pub fn is_local(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains_key(name))
+    }
+
+    /// The type hint of the innermost binding with this name
+    pub fn type_hint(&self, name: &str) -> Option<String> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .cloned()
+            .flatten()
+    }
+}
+
+/// The name of a type, for matching against the type hints of the rules:
+/// the last segment of its path, through references, e.g. `&mut foo::Bar<T>` => `Bar`
+pub fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(type_path) => type_path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string()),
+        Type::Reference(reference) => type_name(&reference.elem),
+        Type::Paren(paren) => type_name(&paren.elem),
+        Type::Group(group) => type_name(&group.elem),
+        _ => None,
+    }
+}
+
+struct PatBindings {
+    names: Vec<String>,
+}
+
+impl<'ast> Visit<'ast> for PatBindings {
+    fn visit_pat_ident(&mut self, node: &'ast PatIdent) {
+        self.names.push(node.ident.to_string());
+        syn::visit::visit_pat_ident(self, node);
+    }
+}
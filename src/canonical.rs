@@ -0,0 +1,123 @@
+use std::fmt;
+use syn::Path;
+
+/// A path reduced to what the rules match on: the segment names and the leading `::`.
+///
+/// Whitespace and generic arguments are not part of it, so `Vec::<u8>::new`,
+/// `Vec :: new` and `Vec::new` are all the same canonical path `Vec::new`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CanonicalPath {
+    pub leading_colon: bool,
+    pub segments: Vec<String>,
+}
+
+impl CanonicalPath {
+    pub fn from_path(path: &Path) -> Self {
+        CanonicalPath {
+            leading_colon: path.leading_colon.is_some(),
+            segments: path
+                .segments
+                .iter()
+                .map(|segment| segment.ident.to_string())
+                .collect(),
+        }
+    }
+
+    /// Parse a path as written by the user in a rule, e.g. "crate1 :: foo" or "::std::vec::Vec<T>"
+    pub fn parse(text: &str) -> Self {
+        // drop the whitespace and the generic arguments
+        let mut stripped = String::with_capacity(text.len());
+        let mut depth = 0;
+        for c in text.chars() {
+            match c {
+                '<' => depth += 1,
+                '>' if depth > 0 => depth -= 1,
+                _ if depth > 0 || c.is_whitespace() => {}
+                _ => stripped.push(c),
+            }
+        }
+        let leading_colon = stripped.starts_with("::");
+        let segments = stripped
+            .trim_start_matches("::")
+            .split("::")
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect();
+        CanonicalPath {
+            leading_colon,
+            segments,
+        }
+    }
+
+    /// The canonical form of a path written by the user
+    pub fn normalize(text: &str) -> String {
+        Self::parse(text).to_string()
+    }
+
+    /// The first `len` segments, keeping the leading `::`
+    pub fn prefix(&self, len: usize) -> CanonicalPath {
+        CanonicalPath {
+            leading_colon: self.leading_colon,
+            segments: self.segments[..len].to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for CanonicalPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.leading_colon {
+            write!(f, "::")?;
+        }
+        write!(f, "{}", self.segments.join("::"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(path: &CanonicalPath) -> Vec<&str> {
+        path.segments.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn whitespace_is_not_part_of_the_path() {
+        let path = CanonicalPath::parse(" crate1 ::\n  foo ");
+        assert!(!path.leading_colon);
+        assert_eq!(segments(&path), vec!["crate1", "foo"]);
+        assert_eq!(path, CanonicalPath::parse("crate1::foo"));
+        assert_eq!(CanonicalPath::normalize("crate1 :: foo"), "crate1::foo");
+    }
+
+    #[test]
+    fn the_leading_colon_is_kept() {
+        let path = CanonicalPath::parse(":: std :: vec");
+        assert!(path.leading_colon);
+        assert_eq!(segments(&path), vec!["std", "vec"]);
+        assert_eq!(path.to_string(), "::std::vec");
+        assert_ne!(path, CanonicalPath::parse("std::vec"));
+    }
+
+    #[test]
+    fn generic_arguments_are_dropped() {
+        assert_eq!(CanonicalPath::normalize("Vec::<u8>::new"), "Vec::new");
+        assert_eq!(
+            CanonicalPath::normalize("::std::collections::HashMap<K, Vec<V>>"),
+            "::std::collections::HashMap"
+        );
+        let parsed: Path = syn::parse_str("Vec::<Option<u8>>::with_capacity").unwrap();
+        assert_eq!(
+            CanonicalPath::from_path(&parsed),
+            CanonicalPath::parse("Vec :: with_capacity")
+        );
+    }
+
+    #[test]
+    fn a_prefix_keeps_the_leading_colon() {
+        let path = CanonicalPath::parse("::a::b::c");
+        assert_eq!(path.prefix(2).to_string(), "::a::b");
+        assert_eq!(path.prefix(3), path);
+        assert_eq!(path.prefix(0).to_string(), "::");
+        assert_eq!(CanonicalPath::parse("a::b").prefix(1).to_string(), "a");
+    }
+}
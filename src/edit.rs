@@ -0,0 +1,261 @@
+use proc_macro2::{Group, Span, TokenStream, TokenTree};
+use quote::ToTokens;
+use std::ops::Range;
+use syn::parse::Parser;
+
+/// A single textual replacement, expressed in byte offsets of the original source
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+/// The set of edits collected while visiting one file.
+///
+/// Edits are recorded against the spans of the nodes as they were parsed, so the
+/// rest of the file (comments, formatting) is left untouched when they are applied.
+#[derive(Debug, Default, Clone)]
+pub struct SourceEdits {
+    edits: Vec<Edit>,
+}
+
+impl SourceEdits {
+    pub fn new() -> Self {
+        SourceEdits { edits: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.edits.len()
+    }
+
+    /// Record the replacement of the given byte range.
+    ///
+    /// A later edit wins over the earlier ones it covers: this is what happens
+    /// when the visitor first rewrites an inner path and then the whole call.
+    pub fn record(&mut self, range: Range<usize>, text: String) {
+        if self
+            .edits
+            .iter()
+            .any(|e| e.range.start <= range.start && range.end <= e.range.end && e.range != range)
+        {
+            // already covered by an outer replacement
+            return;
+        }
+        self.edits
+            .retain(|e| !(range.start <= e.range.start && e.range.end <= range.end));
+        self.edits.push(Edit { range, text });
+    }
+
+    /// Splice all the recorded edits into the source the spans were taken from.
+    ///
+    /// An edit overlapping partially with an earlier one cannot be applied, the first
+    /// one is kept; the dropped edits are returned along with the new text.
+    pub fn apply(&self, source: &str) -> (String, Vec<Edit>) {
+        let base = parsed_offset(source);
+        let mut edits = self.edits.clone();
+        edits.sort_by_key(|e| e.range.start);
+
+        let mut out = String::with_capacity(source.len());
+        let mut dropped = vec![];
+        let mut pos = 0;
+        for edit in edits {
+            let start = base + edit.range.start;
+            let end = base + edit.range.end;
+            if start < pos {
+                dropped.push(edit);
+                continue;
+            }
+            out.push_str(&source[pos..start]);
+            out.push_str(&edit.text);
+            pos = end;
+        }
+        out.push_str(&source[pos..]);
+        (out, dropped)
+    }
+
+    /// The text of a range of the parsed source with the edits recorded inside it applied.
+    ///
+    /// This is how a node that was already visited is carried over into
+    /// a replacement built around it.
+    pub fn text_of(&self, parsed: &str, range: Range<usize>) -> String {
+        let mut edits: Vec<&Edit> = self
+            .edits
+            .iter()
+            .filter(|e| range.start <= e.range.start && e.range.end <= range.end)
+            .collect();
+        edits.sort_by_key(|e| e.range.start);
+
+        let mut out = String::new();
+        let mut pos = range.start;
+        for edit in edits {
+            if edit.range.start < pos {
+                continue;
+            }
+            out.push_str(&parsed[pos..edit.range.start]);
+            out.push_str(&edit.text);
+            pos = edit.range.end;
+        }
+        out.push_str(&parsed[pos..range.end]);
+        out
+    }
+}
+
+/// The part of the source that `syn::parse_file` tokenizes, which the spans are relative to
+pub fn parsed_text(source: &str) -> &str {
+    &source[parsed_offset(source)..]
+}
+
+/// The line and column, both starting at 1, of a byte offset of the parsed source
+pub fn line_column(parsed: &str, offset: usize) -> (usize, usize) {
+    let before = &parsed[..offset];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// The byte offset at which `syn::parse_file` starts tokenizing,
+/// after skipping the BOM and the shebang line.
+fn parsed_offset(source: &str) -> usize {
+    const BOM: &str = "\u{feff}";
+    let mut offset = 0;
+    if source.starts_with(BOM) {
+        offset += BOM.len();
+    }
+    let content = &source[offset..];
+    if content.starts_with("#!") && !content[2..].trim_start().starts_with('[') {
+        offset += content.find('\n').unwrap_or(content.len());
+    }
+    offset
+}
+
+/// Byte range of a node in the parsed source, or None if the node was synthesized
+pub fn node_range<T: ToTokens>(node: &T) -> Option<Range<usize>> {
+    let mut tokens = node.to_token_stream().into_iter();
+    let first = tokens.next()?;
+    let last = tokens.last().unwrap_or_else(|| first.clone());
+    let start = first.span().byte_range();
+    let end = last.span().byte_range();
+    if start.is_empty() || end.is_empty() {
+        return None;
+    }
+    Some(start.start..end.end)
+}
+
+fn respan(stream: TokenStream) -> TokenStream {
+    stream
+        .into_iter()
+        .map(|tt| match tt {
+            TokenTree::Group(g) => {
+                let mut group = Group::new(g.delimiter(), respan(g.stream()));
+                group.set_span(Span::call_site());
+                TokenTree::Group(group)
+            }
+            mut other => {
+                other.set_span(Span::call_site());
+                other
+            }
+        })
+        .collect()
+}
+
+/// Parse the replacement text into a syntax node.
+///
+/// All the tokens get the call site span, so that `node_range` can tell
+/// the replaced nodes apart from the ones that are still in the original source.
+pub fn parse_replacement<T: syn::parse::Parse>(text: &str) -> syn::Result<T> {
+    parse_replacement_with(T::parse, text)
+}
+
+/// Like `parse_replacement`, for the fragments without a `Parse` impl,
+/// e.g. statements with `Block::parse_within`
+pub fn parse_replacement_with<P: Parser>(parser: P, text: &str) -> syn::Result<P::Output> {
+    let tokens: TokenStream = text.parse()?;
+    parser.parse2(respan(tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edits(recorded: &[(Range<usize>, &str)]) -> SourceEdits {
+        let mut edits = SourceEdits::new();
+        for (range, text) in recorded {
+            edits.record(range.clone(), text.to_string());
+        }
+        edits
+    }
+
+    #[test]
+    fn an_outer_edit_replaces_the_inner_ones() {
+        // `a::f(b)`: the callee is rewritten, then the whole call
+        let source = "a::f(b);";
+        let edits = edits(&[(0..4, "x::g"), (0..7, "h(b)")]);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits.apply(source).0, "h(b);");
+    }
+
+    #[test]
+    fn an_inner_edit_inside_an_outer_one_is_ignored() {
+        let edits = edits(&[(0..7, "h(b)"), (5..6, "c")]);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits.apply("a::f(b);").0, "h(b);");
+    }
+
+    #[test]
+    fn the_same_range_recorded_twice_keeps_the_last_text() {
+        let edits = edits(&[(0..4, "x::g"), (0..4, "y::g")]);
+        assert_eq!(edits.apply("a::f(b);").0, "y::g(b);");
+    }
+
+    #[test]
+    fn partially_overlapping_edits_are_dropped() {
+        let edits = edits(&[(0..4, "x::g"), (3..6, "k(c")]);
+        assert_eq!(edits.len(), 2);
+        let (text, dropped) = edits.apply("a::f(b);");
+        assert_eq!(text, "x::g(b);");
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].range, 3..6);
+    }
+
+    #[test]
+    fn text_of_applies_the_edits_inside_the_range() {
+        let source = "f(a::b, c::d) + e::f";
+        let edits = edits(&[(2..6, "x"), (8..12, "y"), (16..20, "z")]);
+        assert_eq!(edits.text_of(source, 0..13), "f(x, y)");
+        assert_eq!(edits.text_of(source, 8..20), "y) + z");
+        assert_eq!(edits.text_of(source, 3..13), "::b, y)");
+    }
+
+    #[test]
+    fn parsed_offset_skips_the_bom_and_the_shebang() {
+        assert_eq!(parsed_offset("fn main() {}"), 0);
+        assert_eq!(parsed_offset("\u{feff}fn main() {}"), 3);
+        assert_eq!(parsed_offset("#!/usr/bin/env run\nfn main() {}"), 18);
+        assert_eq!(parsed_offset("\u{feff}#!/bin/x\nfn main() {}"), 11);
+        // an inner attribute is not a shebang
+        assert_eq!(parsed_offset("#![allow(unused)]\nfn main() {}"), 0);
+        assert_eq!(parsed_offset("#! [allow(unused)]\nfn main() {}"), 0);
+    }
+
+    #[test]
+    fn edits_are_applied_after_the_shebang() {
+        let source = "\u{feff}#!/bin/x\nfn main() { f(); }";
+        let parsed = parsed_text(source);
+        let start = parsed.find("f()").unwrap();
+        let edits = edits(&[(start..start + 1, "g")]);
+        assert_eq!(
+            edits.apply(source).0,
+            "\u{feff}#!/bin/x\nfn main() { g(); }"
+        );
+    }
+
+    #[test]
+    fn line_column_counts_characters_from_one() {
+        let parsed = "\nfn é() {\n    f();\n}";
+        assert_eq!(line_column(parsed, 0), (1, 1));
+        assert_eq!(line_column(parsed, parsed.find('(').unwrap()), (2, 5));
+        assert_eq!(line_column(parsed, parsed.find("f(").unwrap()), (3, 5));
+    }
+}
@@ -0,0 +1,146 @@
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use std::path::{Path, PathBuf};
+
+/// Expand the input paths into the list of Rust files to work on.
+///
+/// Each input can be a file, a directory (all the `.rs` files below it),
+/// or a glob pattern like `crates/*/src/**/*.rs`. Directories and patterns
+/// are walked respecting `.gitignore` unless `respect_ignore` is false;
+/// files named explicitly are always taken.
+pub fn expand_inputs(
+    inputs: &[String],
+    respect_ignore: bool,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = vec![];
+    for input in inputs {
+        let input = input.trim_start_matches("./");
+        let path = Path::new(input);
+        let found = if path.is_file() {
+            vec![path.to_path_buf()]
+        } else if path.is_dir() {
+            walk(path, None, respect_ignore)
+        } else if is_glob(input) {
+            let matcher = glob_matcher(input)?;
+            walk(&glob_base(input), Some(&matcher), respect_ignore)
+        } else {
+            return Err(format!("{}: no such file or directory", input).into());
+        };
+        if found.is_empty() {
+            eprintln!("Warning: no Rust files found for {}", input);
+        }
+        for file in found {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// The directories matching a pattern like `crates/*`, for the members of a workspace.
+/// A pattern without wildcards is the directory itself.
+pub fn matching_dirs(pattern: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    // the walked paths have no leading `./`, so the pattern must not have one either
+    let pattern = pattern.trim_start_matches("./");
+    if !is_glob(pattern) {
+        let dir = PathBuf::from(pattern);
+        return Ok(if dir.is_dir() { vec![dir] } else { vec![] });
+    }
+    let matcher = glob_matcher(pattern)?;
+    let base = glob_base(pattern);
+    // only as deep as the pattern goes, unless it has a `**`
+    let depth = Path::new(pattern).components().count() - base.components().count();
+    let mut dirs: Vec<PathBuf> = WalkBuilder::new(&base)
+        .standard_filters(false)
+        .max_depth((!pattern.contains("**")).then_some(depth))
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .map(|entry| {
+            let path = entry.into_path();
+            path.strip_prefix("./")
+                .map(|p| p.to_path_buf())
+                .unwrap_or(path)
+        })
+        .filter(|path| matcher.is_match(path))
+        .collect();
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// The set of --exclude patterns
+pub struct Excludes {
+    set: GlobSet,
+}
+
+impl Excludes {
+    pub fn new(patterns: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(
+                GlobBuilder::new(pattern.trim_start_matches("./"))
+                    .literal_separator(true)
+                    .build()?,
+            );
+        }
+        Ok(Excludes {
+            set: builder.build()?,
+        })
+    }
+
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let path = path.strip_prefix("./").unwrap_or(path);
+        // a pattern matching a directory excludes everything below it
+        path.ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .any(|p| self.set.is_match(p))
+    }
+}
+
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '[', '{'])
+}
+
+fn glob_matcher(pattern: &str) -> Result<GlobMatcher, globset::Error> {
+    Ok(GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher())
+}
+
+// The longest leading part of the pattern without wildcards, which is where the walk starts
+fn glob_base(pattern: &str) -> PathBuf {
+    let mut base = PathBuf::new();
+    for component in Path::new(pattern).components() {
+        if is_glob(&component.as_os_str().to_string_lossy()) {
+            break;
+        }
+        base.push(component);
+    }
+    if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    }
+}
+
+fn walk(dir: &Path, matcher: Option<&GlobMatcher>, respect_ignore: bool) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = WalkBuilder::new(dir)
+        .standard_filters(respect_ignore)
+        .require_git(false)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .map(|entry| {
+            let path = entry.into_path();
+            path.strip_prefix("./")
+                .map(|p| p.to_path_buf())
+                .unwrap_or(path)
+        })
+        .filter(|path| path.extension().map(|e| e == "rs").unwrap_or(false))
+        .filter(|path| matcher.map(|m| m.is_match(path)).unwrap_or(true))
+        .collect();
+    files.sort();
+    files
+}
@@ -0,0 +1,124 @@
+use crate::writer::{self, FileWrite};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One file written by a run
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    path: String,
+    original_hash: String,
+    original_content: String,
+    new_content: String,
+}
+
+/// Everything written by one run of the tool
+#[derive(Debug, Serialize, Deserialize)]
+struct Journal {
+    // seconds since the unix epoch
+    timestamp: u64,
+    files: Vec<JournalEntry>,
+}
+
+fn content_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Record the writes about to be made into a new journal file in the journal directory,
+/// returning its path.
+pub fn record(journal_dir: &Path, writes: &[FileWrite]) -> Result<PathBuf, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    let mut files = vec![];
+    for write in writes {
+        // the file the writer replaces, as an absolute path so that undo works from anywhere
+        let path = writer::resolve(write.path)?;
+        files.push(JournalEntry {
+            path: path.display().to_string(),
+            original_hash: content_hash(write.original),
+            original_content: write.original.to_string(),
+            new_content: write.content.to_string(),
+        });
+    }
+    let journal = Journal {
+        timestamp: now.as_secs(),
+        files,
+    };
+
+    fs::create_dir_all(journal_dir)
+        .map_err(|e| format!("Error creating {}: {}", journal_dir.display(), e))?;
+    // zero-padded, so that the names sort in the order of the runs
+    let journal_path = journal_dir.join(format!("{:020}.json", now.as_nanos()));
+    let data = serde_json::to_string_pretty(&journal).map_err(|e| e.to_string())?;
+    fs::write(&journal_path, data)
+        .map_err(|e| format!("Error writing {}: {}", journal_path.display(), e))?;
+    Ok(journal_path)
+}
+
+fn last_journal(journal_dir: &Path) -> Result<Option<PathBuf>, String> {
+    if !journal_dir.exists() {
+        return Ok(None);
+    }
+    let entries = fs::read_dir(journal_dir)
+        .map_err(|e| format!("Error reading {}: {}", journal_dir.display(), e))?;
+    Ok(entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|e| e == "json").unwrap_or(false))
+        .max())
+}
+
+/// Restore the files written by the last run, and drop its journal.
+///
+/// Refuses to do anything if any of the files has changed since that run.
+/// Returns the paths of the restored files.
+pub fn undo_last(journal_dir: &Path) -> Result<Vec<String>, String> {
+    let journal_path = last_journal(journal_dir)?
+        .ok_or_else(|| format!("Nothing to undo, no journal in {}", journal_dir.display()))?;
+    let data = fs::read_to_string(&journal_path)
+        .map_err(|e| format!("Error reading {}: {}", journal_path.display(), e))?;
+    let journal: Journal = serde_json::from_str(&data)
+        .map_err(|e| format!("Error parsing {}: {}", journal_path.display(), e))?;
+
+    let mut changed = vec![];
+    for entry in &journal.files {
+        if content_hash(&entry.original_content) != entry.original_hash {
+            return Err(format!(
+                "Journal {} is corrupted for {}",
+                journal_path.display(),
+                entry.path
+            ));
+        }
+        match fs::read_to_string(&entry.path) {
+            Ok(current) if current == entry.new_content => {}
+            _ => changed.push(entry.path.clone()),
+        }
+    }
+    if !changed.is_empty() {
+        return Err(format!(
+            "Refusing to undo, these files changed since the run at {}: {}",
+            journal.timestamp,
+            changed.join(", ")
+        ));
+    }
+
+    let writes: Vec<FileWrite> = journal
+        .files
+        .iter()
+        .map(|entry| FileWrite {
+            path: &entry.path,
+            original: &entry.new_content,
+            content: &entry.original_content,
+        })
+        .collect();
+    writer::write_all(&writes)?;
+    fs::remove_file(&journal_path)
+        .map_err(|e| format!("Error removing {}: {}", journal_path.display(), e))?;
+    Ok(journal.files.into_iter().map(|entry| entry.path).collect())
+}
@@ -0,0 +1,149 @@
+use proc_macro2::TokenStream;
+use quote::ToTokens;
+use syn::parse::{ParseStream, Parser};
+use syn::punctuated::Punctuated;
+use syn::visit_mut::VisitMut;
+use syn::{Block, Expr, Stmt, Token};
+
+/// The arguments of a macro invocation, when they parse as ordinary Rust:
+/// `vec![a, b]`, `format!("{}", x)`, `assert_eq!(a, b)`, `vec![x; n]`
+/// or a body of statements like the ones of our `define_*` macros.
+///
+/// The tokens keep their spans through the parse, so the nodes found in there
+/// can be replaced like any other node of the file.
+pub enum MacroArgs {
+    Exprs(Punctuated<Expr, Token![,]>),
+    Repeat(Box<Expr>, Token![;], Box<Expr>),
+    Stmts(Vec<Stmt>),
+}
+
+impl MacroArgs {
+    /// Try the forms in turn, None if the arguments are not any of them
+    pub fn parse(tokens: &TokenStream) -> Option<Self> {
+        if tokens.is_empty() {
+            return None;
+        }
+        if let Ok(exprs) = Punctuated::<Expr, Token![,]>::parse_terminated.parse2(tokens.clone()) {
+            return Some(MacroArgs::Exprs(exprs));
+        }
+        let repeat = |input: ParseStream| -> syn::Result<Self> {
+            Ok(MacroArgs::Repeat(
+                input.parse()?,
+                input.parse()?,
+                input.parse()?,
+            ))
+        };
+        if let Ok(args) = repeat.parse2(tokens.clone()) {
+            return Some(args);
+        }
+        Block::parse_within
+            .parse2(tokens.clone())
+            .ok()
+            .map(MacroArgs::Stmts)
+    }
+
+    pub fn visit_with<V: VisitMut>(&mut self, visitor: &mut V) {
+        match self {
+            MacroArgs::Exprs(exprs) => {
+                for expr in exprs.iter_mut() {
+                    visitor.visit_expr_mut(expr);
+                }
+            }
+            MacroArgs::Repeat(value, _, count) => {
+                visitor.visit_expr_mut(value);
+                visitor.visit_expr_mut(count);
+            }
+            MacroArgs::Stmts(stmts) => {
+                for stmt in stmts.iter_mut() {
+                    visitor.visit_stmt_mut(stmt);
+                }
+            }
+        }
+    }
+}
+
+impl ToTokens for MacroArgs {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        match self {
+            MacroArgs::Exprs(exprs) => exprs.to_tokens(tokens),
+            MacroArgs::Repeat(value, semi, count) => {
+                value.to_tokens(tokens);
+                semi.to_tokens(tokens);
+                count.to_tokens(tokens);
+            }
+            MacroArgs::Stmts(stmts) => {
+                for stmt in stmts {
+                    stmt.to_tokens(tokens);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // renames the identifier `a` to `z` wherever it is visited
+    struct RenameA;
+
+    impl VisitMut for RenameA {
+        fn visit_ident_mut(&mut self, ident: &mut syn::Ident) {
+            if ident == "a" {
+                *ident = syn::Ident::new("z", ident.span());
+            }
+        }
+    }
+
+    fn macro_tokens(source: &str) -> TokenStream {
+        syn::parse_str::<syn::Macro>(source).unwrap().tokens
+    }
+
+    fn rename(source: &str) -> Option<String> {
+        let mut args = MacroArgs::parse(&macro_tokens(source))?;
+        args.visit_with(&mut RenameA);
+        Some(args.to_token_stream().to_string())
+    }
+
+    #[test]
+    fn lists_and_repeats_are_told_apart() {
+        let list = MacroArgs::parse(&macro_tokens("vec![a, b]"));
+        assert!(matches!(list, Some(MacroArgs::Exprs(ref exprs)) if exprs.len() == 2));
+        assert_eq!(rename("vec![a, b]").unwrap(), "z , b");
+
+        let repeat = MacroArgs::parse(&macro_tokens("vec![a; n]"));
+        assert!(matches!(repeat, Some(MacroArgs::Repeat(..))));
+        assert_eq!(rename("vec![a; n]").unwrap(), "z ; n");
+        assert_eq!(rename("vec![0; a]").unwrap(), "0 ; z");
+    }
+
+    #[test]
+    fn format_and_assert_arguments_are_expressions() {
+        assert_eq!(
+            rename(r#"format!("{} {}", a, a.len())"#).unwrap(),
+            r#""{} {}" , z , z . len ()"#
+        );
+        assert_eq!(
+            rename("assert_eq!(a, f(a), \"{}\", a)").unwrap(),
+            "z , f (z) , \"{}\" , z"
+        );
+    }
+
+    #[test]
+    fn a_body_of_statements_is_visited() {
+        let source = "define_thing! { let x = a; struct S; fn f() { a(); } }";
+        let args = MacroArgs::parse(&macro_tokens(source));
+        assert!(matches!(args, Some(MacroArgs::Stmts(ref stmts)) if stmts.len() == 3));
+        assert_eq!(
+            rename(source).unwrap(),
+            "let x = z ; struct S ; fn f () { z () ; }"
+        );
+    }
+
+    #[test]
+    fn tokens_that_are_not_code_are_not_parsed() {
+        assert!(MacroArgs::parse(&macro_tokens("m!(a => b <= c)")).is_none());
+        assert!(MacroArgs::parse(&macro_tokens("html! { <div class=a></div> }")).is_none());
+        assert!(MacroArgs::parse(&macro_tokens("m!()")).is_none());
+    }
+}
@@ -1,16 +1,44 @@
 use clap::Parser as ClapParser;
-use proc_macro2::{Ident, Span};
+use clap::Subcommand;
+use quote::ToTokens;
+use rayon::prelude::*;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
-use syn::ItemUse;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use syn::UseTree;
-use syn::{PathArguments, PathSegment};
 
-use quote::ToTokens;
-use std::env;
 use std::string::ToString;
-use syn::{Item, ItemFn};
+use syn::{
+    Arm, Block, ExprClosure, ExprForLoop, ExprIf, ExprLet, ExprMethodCall, ExprWhile, FnArg,
+    ImplItemFn, Item, ItemFn, ItemImpl, ItemMod, ItemStruct, ItemTrait, ItemUse, Local, Member,
+    Signature, Stmt, TraitItemFn,
+};
+
+mod bindings;
+mod canonical;
+mod edit;
+mod inputs;
+mod journal;
+mod macro_args;
+mod manifest;
+mod modules;
+mod path_glob;
+mod resolve;
+mod ssr;
+mod template;
+mod writer;
+use bindings::{type_name, LocalBindings};
+use canonical::CanonicalPath;
+use edit::{
+    line_column, node_range, parse_replacement, parse_replacement_with, parsed_text, Edit,
+    SourceEdits,
+};
+use macro_args::MacroArgs;
+use path_glob::PathGlob;
+use resolve::ImportScopes;
+use ssr::{Bound, Matcher, SsrPattern, SsrRule};
+use template::CallTemplate;
 
 #[derive(Debug, Clone, ClapParser, Serialize, Deserialize)]
 struct ReplacementArg {
@@ -43,14 +71,50 @@ impl FromStr for ReplacementArg {
     }
 }
 
+// The options files written before several files could be given have a single string
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(s) => vec![s],
+        StringOrList::List(list) => list,
+    })
+}
+
 /// This program does something useful, but its author needs to edit this.
 /// Else it will be just hanging around forever
 #[derive(Debug, Clone, ClapParser, Serialize, Deserialize)]
 #[clap(version = "0.0.1", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
 struct Opts {
-    /// File to work on
+    /// Files to work on - can be directories or glob patterns like "crates/*/src/**/*.rs", and specified multiple times
     #[clap(short, long)]
-    file_path: Option<String>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    file_path: Vec<String>,
+
+    /// Skip the files matching this glob pattern (can be specified multiple times)
+    #[clap(long)]
+    #[serde(default)]
+    exclude: Vec<String>,
+
+    /// Do not respect .gitignore and hidden files when walking directories
+    #[clap(long)]
+    #[serde(default)]
+    no_ignore: bool,
+
+    /// Crate root (e.g. src/lib.rs) - work on all the module files of the crate
+    #[clap(long)]
+    crate_root: Option<String>,
+
+    /// Cargo.toml of a package or a workspace - work on all the files of all the targets
+    #[clap(long)]
+    manifest_path: Option<String>,
 
     /// Path to a json file containing the bulk replacement config to initialize with
     #[clap(long)]
@@ -64,6 +128,11 @@ struct Opts {
     #[clap(long)]
     callsite_qreplace: Vec<ReplacementArg>,
 
+    /// Call templates - `old::f($a, $b)` to `new::g($b, $a, Default::default())`
+    #[clap(long)]
+    #[serde(default)]
+    callsite_template: Vec<ReplacementArg>,
+
     /// Replacements - full name to a new name
     #[clap(long)]
     path_replace: Vec<ReplacementArg>,
@@ -72,6 +141,66 @@ struct Opts {
     #[clap(long)]
     path_qreplace: Vec<ReplacementArg>,
 
+    /// Regex replacements over full paths, the replacement can refer to the captures ($1, ${name})
+    #[clap(long)]
+    #[serde(default)]
+    path_regex_replace: Vec<ReplacementArg>,
+
+    /// Method call replacements - method name, or Type::method for receivers of that type, to a new name
+    #[clap(long)]
+    #[serde(default)]
+    method_replace: Vec<ReplacementArg>,
+
+    /// Method call replacements - path::Trait::method to a new name, where the trait is in scope
+    #[clap(long)]
+    #[serde(default)]
+    trait_method_replace: Vec<ReplacementArg>,
+
+    /// Method calls to function calls - method or Type::method to path::func, `&path::func` to borrow the receiver
+    #[clap(long)]
+    #[serde(default)]
+    method_to_function: Vec<ReplacementArg>,
+
+    /// Function calls to method calls - path::func to the method name, called on the first argument
+    #[clap(long)]
+    #[serde(default)]
+    function_to_method: Vec<ReplacementArg>,
+
+    /// Macro invocation replacements - macro path to a new path, e.g. println=log::info
+    #[clap(long)]
+    #[serde(default)]
+    macro_replace: Vec<ReplacementArg>,
+
+    /// Trait renames - full trait path to the new one, in bounds, impls, qualified paths and imports
+    #[clap(long)]
+    #[serde(default)]
+    trait_rename: Vec<ReplacementArg>,
+
+    /// Struct field renames - Type::field (or field, for any type) to the new field name
+    #[clap(long)]
+    #[serde(default)]
+    field_rename: Vec<ReplacementArg>,
+
+    /// Enum variant renames - full variant path to the new one, in the same enum or another
+    #[clap(long)]
+    #[serde(default)]
+    variant_rename: Vec<ReplacementArg>,
+
+    /// Replacements - full name to a new name, only where the path is a type or a trait
+    #[clap(long)]
+    #[serde(default)]
+    type_path_replace: Vec<ReplacementArg>,
+
+    /// Replacements - full name to a new name, only where the path is in an expression or a pattern
+    #[clap(long)]
+    #[serde(default)]
+    value_path_replace: Vec<ReplacementArg>,
+
+    /// Structural search and replace - `PATTERN ==>> REPLACEMENT`, Rust fragments with `$name` metavariables
+    #[clap(long)]
+    #[serde(default)]
+    ssr: Vec<String>,
+
     /// File functions mapping
     #[clap(long)]
     file_function_mappings: Vec<ReplacementArg>,
@@ -84,9 +213,38 @@ struct Opts {
     #[clap(short, long)]
     write: bool,
 
+    /// Print a unified diff of the changes instead of the edited code
+    #[clap(long)]
+    #[serde(default)]
+    diff: bool,
+
+    /// Do not write anything, report the files that would change and exit with 1 if there are any
+    #[clap(long)]
+    #[serde(default)]
+    check: bool,
+
+    /// Number of files to process in parallel, defaults to the number of CPUs
+    #[clap(short, long)]
+    jobs: Option<usize>,
+
+    /// Directory where the journal of the writes is kept, for undo;
+    /// defaults to `.tweak-code/journal` in the directory of the project
+    #[clap(long)]
+    #[serde(default)]
+    journal_dir: Option<String>,
+
     /// A level of verbosity, and can be used multiple times
     #[clap(short, long, parse(from_occurrences))]
     verbose: i32,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand, Serialize, Deserialize)]
+enum Command {
+    /// Restore the files written by the last run, unless they changed since
+    Undo,
 }
 
 use std::fs;
@@ -99,52 +257,86 @@ struct CodeReplacer {
     // Optionally: Map from (module::func) to new path for more specific replacements
     qualified_replacements: HashMap<String, String>,
     import_replacements: HashMap<String, String>,
+    // Calls rewritten through a template, binding the arguments
+    call_templates: Vec<CallTemplate>,
 
     // Specific path replacements (highest priority)
     specific_path_replacements: HashMap<String, String>,
+    // Specific path and import replacements with * and ** wildcards, taken out of the maps above
+    glob_path_replacements: Vec<(PathGlob, String)>,
+    glob_import_replacements: Vec<(PathGlob, String)>,
+    // Regex replacements over the full path, after the specific ones
+    regex_path_replacements: Vec<(Regex, String)>,
     // Crate-level replacements (lower priority)
     crate_replacements: HashMap<String, String>,
     file_function_mappings: HashMap<String, String>,
+    // Method name, or Type::method, to the new method name with optional turbofish
+    method_replacements: HashMap<String, String>,
+    // path::Trait::method to the new method name, applied where the trait is in scope
+    trait_method_replacements: HashMap<String, String>,
+    // Method name, or Type::method, to the function the method call becomes
+    method_to_function: HashMap<String, String>,
+    // Function path to the method the call becomes, on its first argument
+    function_to_method: HashMap<String, String>,
+    // Macro path to the new macro path, and the ones with wildcards
+    macro_replacements: HashMap<String, String>,
+    glob_macro_replacements: Vec<(PathGlob, String)>,
+    // Full trait path to the new full trait path
+    trait_renames: HashMap<String, String>,
+    // Type::field, or field for any type, to the new field name
+    field_renames: HashMap<String, String>,
+    // Full enum variant path to the new full variant path
+    variant_renames: HashMap<String, String>,
+    // Path replacements restricted to the types and to the values, tried before the other ones
+    type_path_replacements: HashMap<String, String>,
+    glob_type_path_replacements: Vec<(PathGlob, String)>,
+    value_path_replacements: HashMap<String, String>,
+    glob_value_path_replacements: Vec<(PathGlob, String)>,
+    // Structural search-and-replace rules, tried in order on the expressions, statements and items
+    ssr_rules: Vec<SsrRule>,
 }
 
-impl CodeReplacer {
-    // Helper method to process UseTree nodes recursively
-    fn process_use_tree(&mut self, tree: &mut UseTree) {
-        match tree {
-            // Simple path import: `use some::path;`
-            UseTree::Path(use_path) => {
-                // Check if the path should be replaced
-                let path = &use_path.ident.to_string();
-
-                // Recursively process the subtree
-                self.process_use_tree(&mut *use_path.tree);
-            }
-
-            // Named import: `use some::path::{self, Name, Other}`
-            UseTree::Group(use_group) => {
-                // Process each item in the group
-                for item in &mut use_group.items {
-                    self.process_use_tree(item);
-                }
-            }
-
-            // Full path: `use some::path::Name;`
-            UseTree::Name(use_name) => {
-                // Nothing to do here as we process at the Path level
-            }
-
-            // Glob import: `use some::path::*;`
-            UseTree::Glob(use_glob) => {
-                // Nothing to do here as we process at the Path level
-            }
+/// Where a path is, for the rules restricted to one kind of position
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PathPosition {
+    // types, trait bounds, the trait of an impl
+    Type,
+    // expressions and patterns
+    Value,
+}
 
-            // Rename: `use some::path::Name as OtherName;`
-            UseTree::Rename(use_rename) => {
-                // Nothing to do here as we process at the Path level
-            }
-        }
-    }
+// The new full path of an imported item, given its path and the name it is imported as,
+// if it is renamed
+type ImportRename<'r> = &'r dyn Fn(&CanonicalPath, &str) -> Option<String>;
+
+/// Applies the rules of a CodeReplacer to one file.
+///
+/// The rules are only read, so the same CodeReplacer is shared by all the files
+/// being processed in parallel; the per-file state lives here.
+struct FileReplacer<'a> {
+    replacer: &'a CodeReplacer,
+    // The text the spans of the nodes refer to
+    source: &'a str,
+    // The imports in scope at the current point of the visit
+    scopes: ImportScopes,
+    // The local bindings in scope, which shadow the functions the rules are about
+    locals: LocalBindings,
+    // Matches that were not replaced, with the reason
+    skipped: Vec<String>,
+    // How many of them are matches of the rules the code still has, the calls
+    // of local bindings left aside
+    unreplaced: usize,
+    // Edits to the original source text, collected during the visit
+    edits: SourceEdits,
+    // The self types of the enclosing impl blocks, for the receiver type hints
+    impl_types: Vec<Option<String>>,
+    // The position of the path about to be visited, taken by visit_path_mut
+    position: Option<PathPosition>,
+    // The patterns of the structural search-and-replace rules, parsed for this file
+    ssr_patterns: Vec<(&'a SsrRule, SsrPattern)>,
+}
 
+impl CodeReplacer {
     fn from_config(config_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let config_content = fs::read_to_string(config_path)?;
         let config: serde_json::Value = serde_json::from_str(&config_content)?;
@@ -155,6 +347,19 @@ impl CodeReplacer {
         let mut crate_replacements = HashMap::new();
         let mut specific_path_replacements = HashMap::new();
         let mut file_function_mappings = HashMap::new();
+        let mut regex_path_replacements = vec![];
+        let mut call_templates = vec![];
+        let mut method_replacements = HashMap::new();
+        let mut trait_method_replacements = HashMap::new();
+        let mut method_to_function = HashMap::new();
+        let mut function_to_method = HashMap::new();
+        let mut macro_replacements = HashMap::new();
+        let mut trait_renames = HashMap::new();
+        let mut field_renames = HashMap::new();
+        let mut variant_renames = HashMap::new();
+        let mut type_path_replacements = HashMap::new();
+        let mut value_path_replacements = HashMap::new();
+        let mut ssr_rules = vec![];
 
         // Parse simple replacements
         if let Some(simple) = config.get("callsite_replace").and_then(|v| v.as_object()) {
@@ -174,6 +379,15 @@ impl CodeReplacer {
             }
         }
 
+        // Parse call templates
+        if let Some(templates) = config.get("callsite_template").and_then(|v| v.as_object()) {
+            for (key, value) in templates {
+                if let Some(value_str) = value.as_str() {
+                    call_templates.push(CallTemplate::new(key, value_str)?);
+                }
+            }
+        }
+
         // Parse simple replacements
         if let Some(simple) = config.get("path_replace").and_then(|v| v.as_object()) {
             for (key, value) in simple {
@@ -201,6 +415,117 @@ impl CodeReplacer {
             }
         }
 
+        // Parse regex replacements, in the order they are written
+        if let Some(regexes) = config.get("path_regex_replace").and_then(|v| v.as_object()) {
+            for (key, value) in regexes {
+                if let Some(value_str) = value.as_str() {
+                    regex_path_replacements.push((Regex::new(key)?, value_str.to_string()));
+                }
+            }
+        }
+
+        // Parse method replacements
+        if let Some(methods) = config.get("method_replace").and_then(|v| v.as_object()) {
+            for (key, value) in methods {
+                if let Some(value_str) = value.as_str() {
+                    method_replacements.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse trait method replacements
+        if let Some(methods) = config
+            .get("trait_method_replace")
+            .and_then(|v| v.as_object())
+        {
+            for (key, value) in methods {
+                if let Some(value_str) = value.as_str() {
+                    trait_method_replacements.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse method to function conversions
+        if let Some(methods) = config.get("method_to_function").and_then(|v| v.as_object()) {
+            for (key, value) in methods {
+                if let Some(value_str) = value.as_str() {
+                    method_to_function.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse function to method conversions
+        if let Some(functions) = config.get("function_to_method").and_then(|v| v.as_object()) {
+            for (key, value) in functions {
+                if let Some(value_str) = value.as_str() {
+                    function_to_method.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse macro replacements
+        if let Some(macros) = config.get("macro_replace").and_then(|v| v.as_object()) {
+            for (key, value) in macros {
+                if let Some(value_str) = value.as_str() {
+                    macro_replacements.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse trait renames
+        if let Some(traits) = config.get("trait_rename").and_then(|v| v.as_object()) {
+            for (key, value) in traits {
+                if let Some(value_str) = value.as_str() {
+                    trait_renames.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse field renames
+        if let Some(fields) = config.get("field_rename").and_then(|v| v.as_object()) {
+            for (key, value) in fields {
+                if let Some(value_str) = value.as_str() {
+                    field_renames.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse variant renames
+        if let Some(variants) = config.get("variant_rename").and_then(|v| v.as_object()) {
+            for (key, value) in variants {
+                if let Some(value_str) = value.as_str() {
+                    variant_renames.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse the replacements restricted to types
+        if let Some(qualified) = config.get("type_path_replace").and_then(|v| v.as_object()) {
+            for (key, value) in qualified {
+                if let Some(value_str) = value.as_str() {
+                    type_path_replacements.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse the replacements restricted to values
+        if let Some(qualified) = config.get("value_path_replace").and_then(|v| v.as_object()) {
+            for (key, value) in qualified {
+                if let Some(value_str) = value.as_str() {
+                    value_path_replacements.insert(key.clone(), value_str.to_string());
+                }
+            }
+        }
+
+        // Parse the structural search-and-replace rules
+        if let Some(rules) = config.get("ssr").and_then(|v| v.as_object()) {
+            for (key, value) in rules {
+                if let Some(value_str) = value.as_str() {
+                    ssr_rules.push(SsrRule::new(key, value_str)?);
+                }
+            }
+        }
+
         if let Some(qualified) = config
             .get("file_function_mappings")
             .and_then(|v| v.as_object())
@@ -216,46 +541,162 @@ impl CodeReplacer {
             replacements,
             qualified_replacements,
             import_replacements,
+            call_templates,
             crate_replacements,
             specific_path_replacements,
+            glob_path_replacements: vec![],
+            glob_import_replacements: vec![],
+            regex_path_replacements,
             file_function_mappings,
+            method_replacements,
+            trait_method_replacements,
+            method_to_function,
+            function_to_method,
+            macro_replacements,
+            glob_macro_replacements: vec![],
+            trait_renames,
+            field_renames,
+            variant_renames,
+            type_path_replacements,
+            glob_type_path_replacements: vec![],
+            value_path_replacements,
+            glob_value_path_replacements: vec![],
+            ssr_rules,
         })
     }
 
     fn new() -> Self {
-        let mut replacements = HashMap::new();
+        let replacements = HashMap::new();
         // replacements.insert("foobar".to_string(), "newcrate::blah".to_string());
         // replacements.insert("another_func".to_string(), "newcrate::replacement".to_string());
 
-        let mut qualified_replacements = HashMap::new();
+        let qualified_replacements = HashMap::new();
         // qualified_replacements.insert("module::foobar".to_string(), "newcrate::specific_blah".to_string());
-        let mut import_replacements = HashMap::new();
-        let mut specific_path_replacements = HashMap::new();
+        let import_replacements = HashMap::new();
+        let specific_path_replacements = HashMap::new();
         // specific_path_replacements.insert("crate1::foo".to_string(), "moo".to_string());
         // specific_path_replacements.insert("crate2::bar::baz".to_string(), "newcrate2::qux".to_string());
 
-        let mut crate_replacements = HashMap::new();
+        let crate_replacements = HashMap::new();
         // crate_replacements.insert("crate1".to_string(), "newcrate1".to_string());
-        let mut file_function_mappings = HashMap::new();
+        let file_function_mappings = HashMap::new();
 
         CodeReplacer {
             replacements,
             qualified_replacements,
             import_replacements,
+            call_templates: vec![],
             crate_replacements,
             specific_path_replacements,
+            glob_path_replacements: vec![],
+            glob_import_replacements: vec![],
+            regex_path_replacements: vec![],
             file_function_mappings,
+            method_replacements: HashMap::new(),
+            trait_method_replacements: HashMap::new(),
+            method_to_function: HashMap::new(),
+            function_to_method: HashMap::new(),
+            macro_replacements: HashMap::new(),
+            glob_macro_replacements: vec![],
+            trait_renames: HashMap::new(),
+            field_renames: HashMap::new(),
+            variant_renames: HashMap::new(),
+            type_path_replacements: HashMap::new(),
+            glob_type_path_replacements: vec![],
+            value_path_replacements: HashMap::new(),
+            glob_value_path_replacements: vec![],
+            ssr_rules: vec![],
+        }
+    }
+
+    // Bring the keys of all the rules into the canonical path form, so they match
+    // regardless of how they were written
+    fn normalize_keys(&mut self) {
+        // the macro rules may be written with the `!`
+        self.macro_replacements = self
+            .macro_replacements
+            .drain()
+            .map(|(key, value)| {
+                let key = key.trim().trim_end_matches('!').to_string();
+                (key, value.trim().trim_end_matches('!').to_string())
+            })
+            .collect();
+        for map in [
+            &mut self.replacements,
+            &mut self.qualified_replacements,
+            &mut self.import_replacements,
+            &mut self.specific_path_replacements,
+            &mut self.crate_replacements,
+            &mut self.method_replacements,
+            &mut self.trait_method_replacements,
+            &mut self.method_to_function,
+            &mut self.function_to_method,
+            &mut self.macro_replacements,
+            &mut self.trait_renames,
+            &mut self.variant_renames,
+            &mut self.type_path_replacements,
+            &mut self.value_path_replacements,
+        ] {
+            *map = map
+                .drain()
+                .map(|(key, value)| (CanonicalPath::normalize(&key), value))
+                .collect();
         }
+        self.glob_path_replacements = Self::take_globs(&mut self.specific_path_replacements);
+        self.glob_import_replacements = Self::take_globs(&mut self.import_replacements);
+        // the fields are matched on the name of the type, `a::b::Type::field` is `Type::field`
+        self.field_renames = self
+            .field_renames
+            .drain()
+            .map(|(key, value)| {
+                let path = CanonicalPath::parse(&key);
+                let start = path.segments.len().saturating_sub(2);
+                (path.segments[start..].join("::"), value.trim().to_string())
+            })
+            .collect();
+        self.glob_macro_replacements = Self::take_globs(&mut self.macro_replacements);
+        self.glob_type_path_replacements = Self::take_globs(&mut self.type_path_replacements);
+        self.glob_value_path_replacements = Self::take_globs(&mut self.value_path_replacements);
+    }
+
+    // Move the keys with wildcards out of the map, most specific patterns first
+    fn take_globs(map: &mut HashMap<String, String>) -> Vec<(PathGlob, String)> {
+        let glob_keys: Vec<String> = map
+            .keys()
+            .filter(|key| PathGlob::is_glob(key))
+            .cloned()
+            .collect();
+        let mut globs: Vec<(PathGlob, String)> = glob_keys
+            .into_iter()
+            .map(|key| {
+                let value = map.remove(&key).unwrap();
+                (PathGlob::new(&key), value)
+            })
+            .collect();
+        globs.sort_by(|(a, _), (b, _)| {
+            b.specificity()
+                .cmp(&a.specificity())
+                .then_with(|| a.pattern().cmp(&b.pattern()))
+        });
+        globs
     }
 
-    // Check if this path should be replaced and return the replacement if so
-    fn get_replacement(&self, path: &Path) -> Option<String> {
+    // Check if this path should be replaced and return the replacement if so.
+    // `resolved` are the full paths it may refer to, given the imports in scope.
+    fn get_replacement(&self, path: &Path, resolved: &[CanonicalPath]) -> Option<String> {
         // Try fully qualified path first for more specific matches
         let full_path = path_to_string(path);
         if let Some(replacement) = self.qualified_replacements.get(&full_path) {
             return Some(replacement.clone());
         }
 
+        // Then the path as resolved through the use items
+        for resolved_path in resolved {
+            if let Some(replacement) = self.qualified_replacements.get(&resolved_path.to_string()) {
+                return Some(replacement.clone());
+            }
+        }
+
         // Then try just the function name for more general matches
         if let Some(last_segment) = path.segments.last() {
             let func_name = last_segment.ident.to_string();
@@ -270,21 +711,23 @@ impl CodeReplacer {
     // Get the most appropriate replacement for a path
     fn get_generic_replacement(
         &self,
-        path_str: &str,
+        path: &CanonicalPath,
         specific_path_replacements: &HashMap<String, String>,
+        glob_replacements: &[(PathGlob, String)],
+        regex_replacements: &[(Regex, String)],
         maybe_root_replacements: Option<&HashMap<String, String>>,
     ) -> Option<String> {
-        // println!("TRY REPLACING: {}", &path_str);
-        let path_segments: Vec<String> = path_str.split("::").map(|s| s.to_owned()).collect();
+        // println!("TRY REPLACING: {}", &path);
+        let path_segments = &path.segments;
 
         // First, check for specific path replacements
-        if let Some(replacement) = specific_path_replacements.get(path_str) {
+        if let Some(replacement) = specific_path_replacements.get(&path.to_string()) {
             return Some(replacement.clone());
         }
 
         // Then, check for partial path matches from the start
         for len in (1..path_segments.len()).rev() {
-            let partial_path = path_segments[0..len].join("::");
+            let partial_path = path.prefix(len).to_string();
             // println!("TRY: {}", &partial_path);
             if let Some(replacement) = specific_path_replacements.get(&partial_path) {
                 // Found a partial match - replace prefix and keep the rest
@@ -297,6 +740,36 @@ impl CodeReplacer {
             }
         }
 
+        // Then the wildcard patterns, against the whole path or the longest prefix they match
+        let mut glob_segments = path_segments.clone();
+        if path.leading_colon {
+            glob_segments.insert(0, String::new());
+        }
+        for len in (1..=glob_segments.len()).rev() {
+            for (glob, replacement) in glob_replacements {
+                if let Some(glob_match) = glob.matches(&glob_segments[..len]) {
+                    let replacement = glob_match.substitute(replacement);
+                    if len == glob_segments.len() {
+                        return Some(replacement);
+                    } else {
+                        return Some(format!(
+                            "{}::{}",
+                            replacement,
+                            glob_segments[len..].join("::")
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Then the regexes over the whole path, the first one that matches wins
+        let path_str = path.to_string();
+        for (regex, replacement) in regex_replacements {
+            if regex.is_match(&path_str) {
+                return Some(regex.replace(&path_str, replacement.as_str()).to_string());
+            }
+        }
+
         if let Some(root_replacements) = maybe_root_replacements {
             // If no specific match is found, try crate-level replacements
             if let Some(first_segment) = path_segments.first() {
@@ -308,50 +781,236 @@ impl CodeReplacer {
                     // Replace the first part (crate name) with its replacement
                     path_parts[0] = crate_replacement.clone();
 
-                    return Some(path_parts.join("::"));
+                    let leading_colon = if path.leading_colon { "::" } else { "" };
+                    return Some(format!("{}{}", leading_colon, path_parts.join("::")));
                 }
             }
         }
 
         None
     }
-    fn get_path_replacement(&self, path: &str) -> Option<String> {
+    fn get_path_replacement(&self, path: &CanonicalPath) -> Option<String> {
         self.get_generic_replacement(
             path,
             &self.specific_path_replacements,
+            &self.glob_path_replacements,
+            &self.regex_path_replacements,
             Some(&self.crate_replacements),
         )
     }
 
-    fn get_import_replacement(&self, path: &str) -> Option<String> {
-        // println!("GET IMPORT REPLACEMENT: {}", path);
-        // self.import_replacements.get(path).cloned()
-        let ret = self.get_generic_replacement(path, &self.import_replacements, None);
-        // println!("REPLACEMENT: {:?}", &ret);
-        ret
-    }
-
-    /// Converts a String into a Syn Path
-    ///
-    /// This function takes a dot-separated path string (like "std::collections::HashMap")
-    /// and converts it into a Syn Path structure.
-    fn string_to_path(path_str: &str) -> Path {
-        // Split the path string by "::" to get segments
-        let segments: Vec<PathSegment> = path_str
-            .split("::")
-            .map(|segment| PathSegment {
-                ident: Ident::new(segment, Span::call_site()),
-                arguments: PathArguments::None,
+    // The rules for the position of the path, then the ones for any path.
+    // In value position only the whole path is a value: its leading segments are
+    // a type or a module, e.g. `Foo` in `Foo::new`, and get the type rules.
+    fn get_positional_path_replacement(
+        &self,
+        path: &CanonicalPath,
+        position: Option<PathPosition>,
+    ) -> Option<String> {
+        let positional = match position {
+            Some(PathPosition::Type) => self.get_generic_replacement(
+                path,
+                &self.type_path_replacements,
+                &self.glob_type_path_replacements,
+                &[],
+                None,
+            ),
+            Some(PathPosition::Value) => self
+                .get_whole_path_replacement(
+                    path,
+                    &self.value_path_replacements,
+                    &self.glob_value_path_replacements,
+                )
+                .or_else(|| {
+                    let (last, leading) = path.segments.split_last()?;
+                    if leading.is_empty() {
+                        return None;
+                    }
+                    self.get_generic_replacement(
+                        &path.prefix(leading.len()),
+                        &self.type_path_replacements,
+                        &self.glob_type_path_replacements,
+                        &[],
+                        None,
+                    )
+                    .map(|replacement| format!("{}::{}", replacement, last))
+                }),
+            None => None,
+        };
+        positional.or_else(|| self.get_path_replacement(path))
+    }
+
+    // A rule for exactly this path, not one of its prefixes
+    fn get_whole_path_replacement(
+        &self,
+        path: &CanonicalPath,
+        specific_replacements: &HashMap<String, String>,
+        glob_replacements: &[(PathGlob, String)],
+    ) -> Option<String> {
+        if let Some(replacement) = specific_replacements.get(&path.to_string()) {
+            return Some(replacement.clone());
+        }
+        let mut glob_segments = path.segments.clone();
+        if path.leading_colon {
+            glob_segments.insert(0, String::new());
+        }
+        glob_replacements.iter().find_map(|(glob, replacement)| {
+            glob.matches(&glob_segments)
+                .map(|glob_match| glob_match.substitute(replacement))
+        })
+    }
+
+    fn get_import_replacement(&self, path: &CanonicalPath) -> Option<String> {
+        self.get_generic_replacement(
+            path,
+            &self.import_replacements,
+            &self.glob_import_replacements,
+            &[],
+            None,
+        )
+    }
+
+    // The new path of a macro: the path as written first, then as resolved through the imports
+    fn get_macro_replacement(
+        &self,
+        path: &CanonicalPath,
+        resolved: &[CanonicalPath],
+    ) -> Option<String> {
+        std::iter::once(path).chain(resolved).find_map(|candidate| {
+            self.get_generic_replacement(
+                candidate,
+                &self.macro_replacements,
+                &self.glob_macro_replacements,
+                &[],
+                None,
+            )
+        })
+    }
+
+    // The new path of an imported macro: only the rules for exactly this path, as
+    // a wildcard rule would move the items imported along with the macro too
+    fn get_macro_import_replacement(&self, path: &CanonicalPath) -> Option<String> {
+        self.macro_replacements.get(&path.to_string()).cloned()
+    }
+
+    // The new name of a method at a call, given the type of the receiver if known.
+    // The rules for the type of the receiver come first, then the ones for the traits
+    // in scope, then the ones for the method name alone.
+    fn get_method_replacement(
+        &self,
+        method: &str,
+        receiver_type: Option<&str>,
+        scopes: &ImportScopes,
+    ) -> Option<String> {
+        if let Some(receiver_type) = receiver_type {
+            let key = format!("{}::{}", receiver_type, method);
+            if let Some(replacement) = self.method_replacements.get(&key) {
+                return Some(replacement.clone());
+            }
+        }
+
+        let trait_replacement = self
+            .trait_method_replacements
+            .iter()
+            .filter(|(key, _)| {
+                let path = CanonicalPath::parse(key);
+                match path.segments.last() {
+                    Some(name) if name == method && path.segments.len() > 1 => {
+                        scopes.is_in_scope(&path.prefix(path.segments.len() - 1))
+                    }
+                    _ => false,
+                }
+            })
+            .min_by_key(|(key, _)| key.as_str());
+        if let Some((_, replacement)) = trait_replacement {
+            return Some(replacement.clone());
+        }
+
+        self.method_replacements.get(method).cloned()
+    }
+
+    // The new name of a field, given the name of the struct type if known
+    fn get_field_rename(&self, field: &str, struct_type: Option<&str>) -> Option<String> {
+        if let Some(struct_type) = struct_type {
+            let key = format!("{}::{}", struct_type, field);
+            if let Some(new_name) = self.field_renames.get(&key) {
+                return Some(new_name.clone());
+            }
+        }
+        self.field_renames.get(field).cloned()
+    }
+
+    // Whether a field of this name is renamed for some types only
+    fn has_typed_field_rename(&self, field: &str) -> bool {
+        let suffix = format!("::{}", field);
+        self.field_renames.keys().any(|key| key.ends_with(&suffix))
+    }
+
+    // The template for a call with this many arguments, looked up like the call site
+    // replacements: the path as written, then as resolved, then the name alone
+    fn get_call_template(
+        &self,
+        path: &Path,
+        resolved: &[CanonicalPath],
+        arity: usize,
+    ) -> Option<&CallTemplate> {
+        let canonical = CanonicalPath::from_path(path);
+        let name = CanonicalPath::parse(&path.segments.last()?.ident.to_string());
+        std::iter::once(&canonical)
+            .chain(resolved)
+            .chain(std::iter::once(&name))
+            .find_map(|candidate| {
+                self.call_templates
+                    .iter()
+                    .find(|t| t.path == *candidate && t.params.len() == arity)
             })
+    }
+
+    // The numbers of arguments the templates of a call take, for a call that fits none of them
+    fn call_template_arities(&self, path: &Path, resolved: &[CanonicalPath]) -> Vec<usize> {
+        let canonical = CanonicalPath::from_path(path);
+        let Some(last) = path.segments.last() else {
+            return vec![];
+        };
+        let name = CanonicalPath::parse(&last.ident.to_string());
+        let mut arities: Vec<usize> = self
+            .call_templates
+            .iter()
+            .filter(|t| t.path == canonical || t.path == name || resolved.contains(&t.path))
+            .map(|t| t.params.len())
             .collect();
+        arities.sort();
+        arities.dedup();
+        arities
+    }
+
+    // The function a method call becomes, given the type of the receiver if known
+    fn get_method_to_function(&self, method: &str, receiver_type: Option<&str>) -> Option<String> {
+        if let Some(receiver_type) = receiver_type {
+            let key = format!("{}::{}", receiver_type, method);
+            if let Some(function) = self.method_to_function.get(&key) {
+                return Some(function.clone());
+            }
+        }
+        self.method_to_function.get(method).cloned()
+    }
 
-        Path {
-            leading_colon: None,
-            segments: segments.into_iter().collect(),
+    // The method a function call becomes, looked up like the call site replacements:
+    // the path as written, then as resolved through the imports, then the name alone
+    fn get_function_to_method(&self, path: &Path, resolved: &[CanonicalPath]) -> Option<String> {
+        if let Some(method) = self.function_to_method.get(&path_to_string(path)) {
+            return Some(method.clone());
+        }
+        for resolved_path in resolved {
+            if let Some(method) = self.function_to_method.get(&resolved_path.to_string()) {
+                return Some(method.clone());
+            }
         }
+        let name = path.segments.last()?.ident.to_string();
+        self.function_to_method.get(&name).cloned()
     }
 
-    // Helper to extract the full path from a use tree
+    // Helper to extract the full path from a use tree, None if it ends in a group
     fn extract_use_path_str(&self, tree: &UseTree, prefix: &str) -> Option<String> {
         match tree {
             UseTree::Path(use_path) => {
@@ -378,113 +1037,1509 @@ impl CodeReplacer {
             }
             UseTree::Glob(_) => {
                 if prefix.is_empty() {
-                    Some(format!("*"))
+                    Some("*".to_string())
                 } else {
                     Some(format!("{}::*", prefix))
                 }
             }
-            &UseTree::Group(ref x) => {
-                // println!("GROUP: {:?}", &x);
-                let values = x
-                    .clone()
-                    .items
-                    .into_iter()
-                    .map(|item| self.extract_use_path_str(&item, prefix))
-                    .filter_map(|x| x)
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                // println!("VALUES: {}", &values);
-                Some(format!("{{ {} }}", &values))
-            }
+            // the names in a group are matched one by one
+            UseTree::Group(_) => None,
         }
     }
-    fn extract_use_path(&self, tree: &UseTree, prefix: &str) -> Option<Path> {
-        self.extract_use_path_str(tree, prefix)
-            .map(|s| Self::string_to_path(&s))
-    }
 }
 
-impl VisitMut for CodeReplacer {
-    fn visit_expr_call_mut(&mut self, node: &mut ExprCall) {
-        // First visit any inner call expressions
-        syn::visit_mut::visit_expr_call_mut(self, node);
-
-        // Check if this is a call to a function we want to replace
-        if let Expr::Path(ExprPath { path, .. }) = &mut *node.func {
-            if let Some(replacement) = self.get_replacement(path) {
-                // Replace with the new path
-                *path = syn::parse_str::<Path>(&replacement).unwrap();
-            }
+impl<'a> FileReplacer<'a> {
+    fn new(replacer: &'a CodeReplacer, scopes: ImportScopes, source: &'a str) -> Self {
+        FileReplacer {
+            replacer,
+            source,
+            scopes,
+            locals: LocalBindings::new(),
+            skipped: vec![],
+            unreplaced: 0,
+            edits: SourceEdits::new(),
+            impl_types: vec![],
+            position: None,
+            ssr_patterns: replacer
+                .ssr_rules
+                .iter()
+                .map(|rule| (rule, rule.parse_pattern()))
+                .collect(),
         }
     }
 
-    // Now properly handle use tree replacements
-    fn visit_use_tree_mut(&mut self, node: &mut UseTree) {
-        let node_copy = node.clone();
-        // println!("USE_TREE: {:?}", &node_copy);
-        match node {
-            UseTree::Path(ref mut use_path) => {
-                // First, get the full path up to this point
-                if let Some(path_str) = self.extract_use_path_str(&node_copy, "") {
-                    // Check if this path should be completely replaced
-                    if let Some(replacement) = self.get_import_replacement(&path_str) {
-                        // Replace the entire use tree
-                        *node = syn::parse_str::<UseTree>(&replacement).unwrap();
-                        println!("Replace the node, new node: {:?}", &node);
-                        return; // Skip further visitation
-                    }
-                }
-
-                // Otherwise, continue with normal visitation
-                // self.visit_use_tree_mut(&mut *use_path.tree);
-            }
-            UseTree::Group(use_group) => {
-                // Process each item in the group
-                let mut i = 0;
-                while i < use_group.items.len() {
-                    // Extract the full path for this item
-                    if let Some(path_str) = self.extract_use_path_str(&use_group.items[i], "") {
-                        if let Some(replacement) = self.get_import_replacement(&path_str) {
-                            // Replace this item
-                            use_group.items[i] = syn::parse_str::<UseTree>(&replacement).unwrap();
-                        }
-                    }
-
-                    // Visit the item for nested replacements
-                    self.visit_use_tree_mut(&mut use_group.items[i]);
-                    i += 1;
+    // The name of the type of an expression, where it is plain to see:
+    // a local binding with a known type, `self`, `Type::new(..)` or `Type { .. }`
+    fn expr_type_hint(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Path(ExprPath { path, .. }) if path.segments.len() == 1 => {
+                let name = path.segments[0].ident.to_string();
+                if name == "self" {
+                    self.impl_types.last().cloned().flatten()
+                } else {
+                    self.locals.type_hint(&name)
                 }
             }
-            UseTree::Name(use_name) => {
-                // Extract the full path including this name
-                if let Some(path_str) = self.extract_use_path_str(node, "") {
-                    if let Some(replacement) = self.get_import_replacement(&path_str) {
-                        // Replace with the new use tree
-                        *node = syn::parse_str::<UseTree>(&replacement).unwrap();
+            // only `new`: the other associated functions may return anything, e.g. `String::from_utf8`
+            Expr::Call(ExprCall { func, .. }) => match &**func {
+                Expr::Path(ExprPath { path, .. })
+                    if path.segments.len() > 1 && path.segments.last()?.ident == "new" =>
+                {
+                    let segment = &path.segments[path.segments.len() - 2];
+                    let name = segment.ident.to_string();
+                    if name == "Self" {
+                        self.impl_types.last().cloned().flatten()
+                    } else {
+                        Some(name)
                     }
                 }
-            }
-            UseTree::Rename(use_rename) => {
-                // Similar to Name, but with rename info
-                if let Some(parent_path) = self.extract_use_path(node, "") {
-                    let path_str = path_to_string(&parent_path);
-                    if let Some(replacement) = self.get_import_replacement(&path_str) {
-                        // Replace with the new use tree
-                        *node = syn::parse_str::<UseTree>(&replacement).unwrap();
-                    }
+                _ => None,
+            },
+            Expr::Struct(expr_struct) => {
+                let name = expr_struct.path.segments.last()?.ident.to_string();
+                if name == "Self" {
+                    self.impl_types.last().cloned().flatten()
+                } else {
+                    Some(name)
                 }
             }
-            UseTree::Glob(_) => {
-                // No specific replacements for glob imports
+            Expr::Reference(reference) => self.expr_type_hint(&reference.expr),
+            Expr::Paren(paren) => self.expr_type_hint(&paren.expr),
+            _ => None,
+        }
+    }
+
+    // The name of the path if it is a local binding, which shadows any rule for it
+    fn local_name(&self, path: &Path) -> Option<String> {
+        if path.leading_colon.is_some() || path.segments.len() != 1 {
+            return None;
+        }
+        let name = path.segments[0].ident.to_string();
+        if self.locals.is_local(&name) {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    fn report_skipped(&mut self, path: &Path, reason: String) {
+        if node_range(path).is_none() {
+            // not in the original source
+            return;
+        }
+        self.report_skipped_at(path.segments[0].ident.span(), reason);
+    }
+
+    // A path that matches a rule but refers to a local binding: not a match after all
+    fn report_local_binding(&mut self, path: &Path, reason: String) {
+        if node_range(path).is_none() {
+            return;
+        }
+        self.push_skipped(path.segments[0].ident.span(), reason);
+    }
+
+    // The trait rename rule for a path, if it refers to one of the renamed traits:
+    // the old full path and the new one
+    fn trait_rename(&self, path: &CanonicalPath) -> Option<(CanonicalPath, String)> {
+        let renames = &self.replacer.trait_renames;
+        if renames.is_empty() {
+            return None;
+        }
+        std::iter::once(path.clone())
+            .chain(self.scopes.resolve(path))
+            .find_map(|candidate| {
+                let new = renames.get(&candidate.to_string())?;
+                Some((candidate, new.clone()))
+            })
+    }
+
+    // Rename the leading segments of a path that refer to a renamed trait:
+    // `OldTrait`, `old::OldTrait<T>`, `OldTrait::method`, `<T as OldTrait>::Item`.
+    // Only the names are replaced, the generic arguments stay.
+    fn rename_trait_prefix(&mut self, path: &mut Path) -> bool {
+        let canonical = CanonicalPath::from_path(path);
+        for len in (1..=canonical.segments.len()).rev() {
+            let written = canonical.prefix(len);
+            let Some((old, new)) = self.trait_rename(&written) else {
+                continue;
+            };
+            let new_text = renamed_trait_path(&written, &old, &new);
+            if new_text == written.to_string() {
+                return false;
+            }
+            let Ok(mut new_path) = parse_replacement::<Path>(&new_text) else {
+                let reason = format!("trait `{}` not renamed, `{}` is not a path", old, new);
+                self.report_skipped(path, reason);
+                return false;
+            };
+            let range = node_range(path);
+            let arguments = path.segments[len - 1].arguments.clone();
+            new_path.segments.last_mut().unwrap().arguments = arguments;
+            new_path
+                .segments
+                .extend(path.segments.iter().skip(len).cloned());
+            if let Some(range) = range {
+                let end = path.segments[len - 1].ident.span().byte_range().end;
+                self.edits.record(range.start..end, new_text);
+            }
+            *path = new_path;
+            return true;
+        }
+        false
+    }
+
+    // The renames of traits, variants or macros in a use tree, keeping track of the path
+    // leading to each name. `renamed` gives the new full path of an imported item.
+    fn rename_in_use_tree(
+        &mut self,
+        tree: &mut UseTree,
+        renamed: ImportRename,
+        kind: &str,
+        mut prefix: CanonicalPath,
+        top_range: Option<Range<usize>>,
+        in_group: bool,
+    ) {
+        let (ident, alias) = match tree {
+            UseTree::Path(use_path) => {
+                prefix.segments.push(use_path.ident.to_string());
+                self.rename_in_use_tree(
+                    &mut use_path.tree,
+                    renamed,
+                    kind,
+                    prefix,
+                    top_range,
+                    in_group,
+                );
+                return;
+            }
+            UseTree::Group(use_group) => {
+                for item in use_group.items.iter_mut() {
+                    self.rename_in_use_tree(item, renamed, kind, prefix.clone(), None, true);
+                }
+                return;
+            }
+            UseTree::Name(use_name) => (&mut use_name.ident, None),
+            UseTree::Rename(use_rename) => (&mut use_rename.ident, Some(use_rename.rename.clone())),
+            UseTree::Glob(_) => return,
+        };
+        prefix.segments.push(ident.to_string());
+        let local_name = alias.as_ref().unwrap_or(ident).to_string();
+        let absolute = self.scopes.absolute_import(&prefix);
+        let (old, new) = match renamed(&prefix, &local_name) {
+            Some(new) => (prefix, new),
+            None => match renamed(&absolute, &local_name) {
+                Some(new) => (absolute, new),
+                None => return,
+            },
+        };
+        let new_path = CanonicalPath::parse(&new);
+        let Some(new_name) = new_path.segments.last().cloned() else {
+            return;
+        };
+        if same_parent(&old, &new_path) {
+            // renamed in place, only the name changes
+            let Ok(new_ident) = parse_replacement::<syn::Ident>(&new_name) else {
+                let reason = format!(
+                    "import of {} `{}` not renamed, `{}` is not a name",
+                    kind, old, new_name
+                );
+                self.report_skipped_at(ident.span(), reason);
+                return;
+            };
+            if let Some(range) = node_range(ident) {
+                self.edits.record(range, new_name);
+            }
+            *ident = new_ident;
+        } else if let (false, Some(range)) = (in_group, top_range) {
+            // moved, the whole import changes
+            let text = match alias {
+                Some(alias) => format!("{} as {}", new_path, alias),
+                None => new_path.to_string(),
+            };
+            if let Ok(new_tree) = parse_replacement::<UseTree>(&text) {
+                self.edits.record(range, text);
+                *tree = new_tree;
+            }
+        } else {
+            let reason = format!(
+                "import of {} `{}` not renamed, it is moved to `{}` from inside a group",
+                kind, old, new_path
+            );
+            self.report_skipped_at(ident.span(), reason);
+        }
+    }
+
+    // The variant rename rule for a whole path, if it refers to one of the renamed variants:
+    // the old full path and the new one. `Self::Variant` matches the variants of the enum
+    // of the enclosing impl block by name.
+    fn variant_rename(&self, path: &CanonicalPath) -> Option<(CanonicalPath, String)> {
+        let renames = &self.replacer.variant_renames;
+        if renames.is_empty() {
+            return None;
+        }
+        if path.segments.len() == 2 && path.segments[0] == "Self" {
+            let enum_name = self.impl_types.last().cloned().flatten()?;
+            return renames.iter().find_map(|(key, new)| {
+                let old = CanonicalPath::parse(key);
+                let n = old.segments.len();
+                (n >= 2
+                    && old.segments[n - 2] == enum_name
+                    && old.segments[n - 1] == path.segments[1])
+                    .then(|| (old, new.clone()))
+            });
+        }
+        std::iter::once(path.clone())
+            .chain(self.scopes.resolve(path))
+            .find_map(|candidate| {
+                let new = renames.get(&candidate.to_string())?;
+                Some((candidate, new.clone()))
+            })
+    }
+
+    // Rename the path of a variant, in expressions and patterns. A variant renamed
+    // in its enum only gets its last segment replaced, a moved one gets the new full path.
+    fn rename_variant_path(&mut self, path: &mut Path) -> bool {
+        let canonical = CanonicalPath::from_path(path);
+        let Some((old, new)) = self.variant_rename(&canonical) else {
+            return false;
+        };
+        let new_path = CanonicalPath::parse(&new);
+        let (Some(new_name), Some(range)) = (new_path.segments.last().cloned(), node_range(path))
+        else {
+            return false;
+        };
+        let last = path.segments.last_mut().unwrap();
+        if same_parent(&old, &new_path) {
+            if let Some(ident_range) = node_range(&last.ident) {
+                self.edits.record(ident_range, new_name.clone());
+            }
+            last.ident = syn::Ident::new(&new_name, proc_macro2::Span::call_site());
+        } else {
+            let Ok(moved) = parse_replacement::<Path>(&new_path.to_string()) else {
+                return false;
+            };
+            self.edits.record(range, new_path.to_string());
+            *path = moved;
+        }
+        true
+    }
+
+    // The replacement of a path, with the generic arguments of its segments carried over
+    // to the corresponding segments of the new path, matched from the end:
+    // `Vec::<u8>::with_capacity` => `SmallVec::<u8>::with_capacity`, `parse::<u32>` => `str_parse::<u32>`.
+    // The arguments written in the replacement win. Returns the new path and its text, or None
+    // when the new path is too short to take all the arguments.
+    fn path_with_generic_args(&mut self, old: &Path, replacement: &str) -> Option<(Path, String)> {
+        let mut new_path = match parse_replacement::<Path>(replacement) {
+            Ok(new_path) => new_path,
+            Err(e) => {
+                let reason = format!(
+                    "path `{}` not replaced, `{}` is not a path: {}",
+                    path_to_string(old),
+                    replacement,
+                    e
+                );
+                self.report_skipped_at(old.segments[0].ident.span(), reason);
+                return None;
+            }
+        };
+        // the arguments of the leading segments that have no counterpart would be lost
+        let unmatched = old.segments.len().saturating_sub(new_path.segments.len());
+        if let Some(segment) = old
+            .segments
+            .iter()
+            .take(unmatched)
+            .find(|segment| !segment.arguments.is_none())
+        {
+            let reason = format!(
+                "path `{}` not replaced, `{}` has no segment for the generic arguments of `{}`",
+                path_to_string(old),
+                replacement,
+                segment.ident
+            );
+            self.report_skipped_at(old.segments[0].ident.span(), reason);
+            return None;
+        }
+        let mut texts = split_path_segments(replacement);
+        if texts.len() != new_path.segments.len() {
+            return Some((new_path, replacement.to_string()));
+        }
+        let mut carried = false;
+        for ((new_segment, old_segment), text) in new_path
+            .segments
+            .iter_mut()
+            .rev()
+            .zip(old.segments.iter().rev())
+            .zip(texts.iter_mut().rev())
+        {
+            if new_segment.arguments.is_none() && !old_segment.arguments.is_none() {
+                let arguments = &old_segment.arguments;
+                text.push_str(&self.visited_text(arguments, node_range(arguments)));
+                new_segment.arguments = arguments.clone();
+                carried = true;
+            }
+        }
+        if !carried {
+            return Some((new_path, replacement.to_string()));
+        }
+        let leading_colon = if new_path.leading_colon.is_some() {
+            "::"
+        } else {
+            ""
+        };
+        Some((new_path, format!("{}{}", leading_colon, texts.join("::"))))
+    }
+
+    // The name of the struct type of a literal or a pattern
+    fn struct_type_name(&self, path: &Path) -> Option<String> {
+        let name = path.segments.last()?.ident.to_string();
+        if name == "Self" {
+            self.impl_types.last().cloned().flatten()
+        } else {
+            Some(name)
+        }
+    }
+
+    // Rename the field of a struct literal or pattern. A shorthand `field` becomes
+    // `new_name: field`, `value` being the range and the text of the shorthand.
+    fn rename_struct_member(
+        &mut self,
+        member: &mut Member,
+        colon_token: &mut Option<syn::Token![:]>,
+        struct_type: Option<&str>,
+        value_range: Option<Range<usize>>,
+    ) {
+        let Member::Named(ident) = member else {
+            return;
+        };
+        let Some(new_name) = self
+            .replacer
+            .get_field_rename(&ident.to_string(), struct_type)
+        else {
+            return;
+        };
+        let Ok(new_ident) = parse_replacement::<syn::Ident>(&new_name) else {
+            let reason = format!(
+                "field `{}` not renamed, `{}` is not a name",
+                ident, new_name
+            );
+            self.report_skipped_at(ident.span(), reason);
+            return;
+        };
+        if colon_token.is_none() {
+            if let Some(range) = value_range {
+                let value = self.edits.text_of(self.source, range.clone());
+                self.edits.record(range, format!("{}: {}", new_name, value));
+            }
+            *colon_token = Some(Default::default());
+        } else if let Some(range) = node_range(ident) {
+            self.edits.record(range, new_name);
+        }
+        *member = Member::Named(new_ident);
+    }
+
+    // The name a macro call keeps when the macro is imported by name and its import
+    // is renamed along with it: the new name, or the alias of the import
+    fn imported_macro_name(&self, path: &Path) -> Option<String> {
+        let name = path.get_ident()?.to_string();
+        let (full, in_group) = self.scopes.imported_macro(&name)?;
+        let new = CanonicalPath::parse(&self.replacer.get_macro_import_replacement(full)?);
+        if in_group && !same_parent(full, &new) {
+            // the import cannot be moved out of its group
+            return None;
+        }
+        if full.segments.last() == Some(&name) {
+            new.segments.last().cloned()
+        } else {
+            Some(name)
+        }
+    }
+
+    // Visit a path whose position is known
+    fn visit_path_at(&mut self, path: &mut Path, position: PathPosition) {
+        self.position = Some(position);
+        self.visit_path_mut(path);
+    }
+
+    // The text of a node that was already visited, with the replacements made inside it.
+    // `range` is where the node was in the source before the visit.
+    fn visited_text<T: ToTokens>(&self, node: &T, range: Option<Range<usize>>) -> String {
+        match range {
+            Some(range) => self.edits.text_of(self.source, range),
+            None => node.to_token_stream().to_string(),
+        }
+    }
+
+    fn report_skipped_at(&mut self, span: proc_macro2::Span, reason: String) {
+        self.unreplaced += 1;
+        self.push_skipped(span, reason);
+    }
+
+    // An edit that could not be applied, as it overlaps with another one
+    fn report_dropped(&mut self, edit: &Edit) {
+        let (line, column) = line_column(self.source, edit.range.start);
+        let reason = format!(
+            "`{}` not replaced, it overlaps with another replacement",
+            edit.text
+        );
+        self.unreplaced += 1;
+        self.skipped
+            .push(format!("{}:{}: {}", line, column, reason));
+    }
+
+    fn push_skipped(&mut self, span: proc_macro2::Span, reason: String) {
+        let start = span.start();
+        self.skipped
+            .push(format!("{}:{}: {}", start.line, start.column + 1, reason));
+    }
+
+    // `recv.method(args)` => `function(recv, args)`, visiting the receiver and the arguments
+    fn method_call_to_function(
+        &mut self,
+        call: &mut ExprMethodCall,
+        function: &str,
+    ) -> Option<Expr> {
+        let range = node_range(call)?;
+        // `&path::func` and `&mut path::func` borrow the receiver
+        let (borrow, path) = if let Some(path) = function.strip_prefix("&mut ") {
+            ("&mut ", path.trim())
+        } else if let Some(path) = function.strip_prefix('&') {
+            ("&", path.trim())
+        } else {
+            ("", function)
+        };
+        if let Err(e) = parse_replacement::<Path>(path) {
+            let reason = format!(
+                "method call `{}` not converted, `{}` is not a function path: {}",
+                call.method, function, e
+            );
+            self.report_skipped_at(call.method.span(), reason);
+            return None;
+        }
+
+        let receiver_range = node_range(&call.receiver);
+        let arg_ranges: Vec<_> = call.args.iter().map(node_range).collect();
+        self.visit_expr_mut(&mut call.receiver);
+        for arg in call.args.iter_mut() {
+            self.visit_expr_mut(arg);
+        }
+
+        let turbofish = call
+            .turbofish
+            .as_ref()
+            .map(|turbofish| self.visited_text(turbofish, node_range(turbofish)))
+            .unwrap_or_default();
+        let mut args = vec![format!(
+            "{}{}",
+            borrow,
+            self.visited_text(&call.receiver, receiver_range)
+        )];
+        for (arg, arg_range) in call.args.iter().zip(arg_ranges) {
+            args.push(self.visited_text(arg, arg_range));
+        }
+        let text = format!("{}{}({})", path, turbofish, args.join(", "));
+        self.edits.record(range, text.clone());
+        Some(parse_replacement::<Expr>(&text).unwrap_or_else(|_| Expr::MethodCall(call.clone())))
+    }
+
+    // Rewrite a call through a template, visiting the arguments bound to the metavariables
+    fn call_from_template(&mut self, call: &mut ExprCall, template: &CallTemplate) -> Option<Expr> {
+        let range = node_range(call)?;
+        let arg_ranges: Vec<_> = call.args.iter().map(node_range).collect();
+        for arg in call.args.iter_mut() {
+            self.visit_expr_mut(arg);
+        }
+        let bound: HashMap<&str, (String, bool)> = template
+            .params
+            .iter()
+            .zip(call.args.iter().zip(arg_ranges))
+            .map(|(name, (arg, arg_range))| {
+                let text = self.visited_text(arg, arg_range);
+                (name.as_str(), (text, !is_receiver_expr(arg)))
+            })
+            .collect();
+        let text = template::instantiate(&template.template, |name| bound.get(name).cloned());
+        match parse_replacement::<Expr>(&text) {
+            Ok(expr) => {
+                self.edits.record(range, text);
+                Some(expr)
+            }
+            Err(e) => {
+                let reason = format!("call not rewritten, `{}` does not parse: {}", text, e);
+                let span = call.paren_token.span.open();
+                self.report_skipped_at(span, reason);
+                Some(Expr::Call(call.clone()))
+            }
+        }
+    }
+
+    // The first structural search-and-replace rule whose pattern matches the node,
+    // with what its metavariables matched
+    fn ssr_match<F>(&self, matches: F) -> Option<(&'a SsrRule, ssr::Bindings)>
+    where
+        F: Fn(&Matcher, &SsrPattern, &mut ssr::Bindings) -> bool,
+    {
+        let scopes = &self.scopes;
+        let paths_equal = |pattern: &Path, candidate: &Path| {
+            let pattern = CanonicalPath::from_path(pattern);
+            let candidate = CanonicalPath::from_path(candidate);
+            pattern == candidate || scopes.resolve(&candidate).contains(&pattern)
+        };
+        let matcher = Matcher {
+            paths_equal: &paths_equal,
+        };
+        self.ssr_patterns.iter().find_map(|(rule, pattern)| {
+            let mut bindings = ssr::Bindings::new();
+            matches(&matcher, pattern, &mut bindings).then_some((*rule, bindings))
+        })
+    }
+
+    // Fill in the replacement of a rule with the code the metavariables matched,
+    // visiting that code first so the other rules apply inside it
+    fn ssr_instantiate(&mut self, rule: &SsrRule, bindings: ssr::Bindings) -> String {
+        let mut bound = HashMap::new();
+        for (name, node) in bindings {
+            let text = match node {
+                Bound::Expr(mut expr) => {
+                    let range = node_range(&expr);
+                    let needs_parens = !is_receiver_expr(&expr);
+                    self.visit_expr_mut(&mut expr);
+                    (self.visited_text(&expr, range), needs_parens)
+                }
+                Bound::Type(mut ty) => {
+                    let range = node_range(&ty);
+                    self.visit_type_mut(&mut ty);
+                    (self.visited_text(&ty, range), false)
+                }
+                Bound::Pat(mut pat) => {
+                    let range = node_range(&pat);
+                    self.visit_pat_mut(&mut pat);
+                    (self.visited_text(&pat, range), false)
+                }
+                Bound::Tokens(tokens) => (self.visited_text(&tokens, node_range(&tokens)), false),
+            };
+            bound.insert(name, text);
+        }
+        template::instantiate(&rule.template, |name| bound.get(name).cloned())
+    }
+
+    // Replace a node matched by a rule: the replacement must parse as the same kind of code.
+    // None if no rule matches, the node is left as it is if the replacement does not parse.
+    fn ssr_replace<T, M, P>(&mut self, node: &T, matches: M, parse: P) -> Option<T>
+    where
+        T: ToTokens + Clone,
+        M: Fn(&Matcher, &SsrPattern, &mut ssr::Bindings) -> bool,
+        P: Fn(&str) -> syn::Result<T>,
+    {
+        if self.ssr_patterns.is_empty() {
+            return None;
+        }
+        let (rule, bindings) = self.ssr_match(matches)?;
+        let range = node_range(node)?;
+        let text = self.ssr_instantiate(rule, bindings);
+        match parse(&text) {
+            Ok(replacement) => {
+                self.edits.record(range, text);
+                Some(replacement)
+            }
+            Err(e) => {
+                let reason = format!(
+                    "`{}` not replaced, `{}` does not parse: {}",
+                    rule.pattern, text, e
+                );
+                if let Some(token) = node.to_token_stream().into_iter().next() {
+                    self.report_skipped_at(token.span(), reason);
+                }
+                Some(node.clone())
+            }
+        }
+    }
+
+    // `func(first, args)` => `first.method(args)`, visiting the arguments
+    fn function_call_to_method(&mut self, call: &mut ExprCall, method: &str) -> Option<Expr> {
+        let range = node_range(call)?;
+        let Expr::Path(ExprPath { path, .. }) = &*call.func else {
+            return None;
+        };
+        let span = path.segments[0].ident.span();
+        if call.args.is_empty() {
+            let reason = format!(
+                "call to `{}` not converted, there is no argument to call `{}` on",
+                path_to_string(path),
+                method
+            );
+            self.report_skipped_at(span, reason);
+            return None;
+        }
+        if let Err(e) = parse_replacement::<syn::Ident>(method) {
+            let reason = format!(
+                "call to `{}` not converted, `{}` is not a method name: {}",
+                path_to_string(path),
+                method,
+                e
+            );
+            self.report_skipped_at(span, reason);
+            return None;
+        }
+        // the generic arguments of the function go to the method
+        let turbofish = match &path.segments.last()?.arguments {
+            syn::PathArguments::AngleBracketed(args) => {
+                let mut args = args.clone();
+                args.colon2_token = None;
+                format!("::{}", self.visited_text(&args, node_range(&args)))
+            }
+            _ => String::new(),
+        };
+        let arg_ranges: Vec<_> = call.args.iter().map(node_range).collect();
+        for arg in call.args.iter_mut() {
+            self.visit_expr_mut(arg);
+        }
+
+        let mut args = call.args.iter().zip(arg_ranges);
+        let (first, first_range) = args.next()?;
+        // the method call borrows the receiver itself
+        let (receiver, receiver_range) = match first {
+            Expr::Reference(reference) => {
+                let range = node_range(&reference.expr).filter(|_| first_range.is_some());
+                (&*reference.expr, range)
+            }
+            _ => (first, first_range),
+        };
+        let mut receiver_text = self.visited_text(receiver, receiver_range);
+        if !is_receiver_expr(receiver) {
+            receiver_text = format!("({})", receiver_text);
+        }
+        let rest: Vec<String> = args
+            .map(|(arg, arg_range)| self.visited_text(arg, arg_range))
+            .collect();
+        let text = format!(
+            "{}.{}{}({})",
+            receiver_text,
+            method,
+            turbofish,
+            rest.join(", ")
+        );
+        self.edits.record(range, text.clone());
+        Some(parse_replacement::<Expr>(&text).unwrap_or_else(|_| Expr::Call(call.clone())))
+    }
+
+    // A function body: the parameters are the only locals, the ones of an enclosing function are not visible
+    fn visit_fn_body<F: FnOnce(&mut Self)>(&mut self, sig: &Signature, visit: F) {
+        let saved = self.locals.enter_item();
+        self.locals.push();
+        for input in &sig.inputs {
+            if let FnArg::Typed(pat_type) = input {
+                self.locals.add_pat(&pat_type.pat, type_name(&pat_type.ty));
+            }
+        }
+        visit(self);
+        self.locals.leave_item(saved);
+    }
+
+    // Replace a use tree node, recording the edit if the node is from the original source.
+    // A replacement that is not a use tree, e.g. from a wildcard or regex rule, is reported instead.
+    fn replace_use_tree(&mut self, node: &mut UseTree, replacement: String) {
+        let range = node_range(node);
+        match parse_replacement::<UseTree>(&replacement) {
+            Ok(new_tree) => {
+                *node = new_tree;
+                if let Some(range) = range {
+                    self.edits.record(range, replacement);
+                }
+            }
+            Err(e) => {
+                let first_token = node.to_token_stream().into_iter().next();
+                if let (Some(_), Some(token)) = (range, first_token) {
+                    let reason = format!(
+                        "import not replaced, `{}` is not a use tree: {}",
+                        replacement, e
+                    );
+                    self.report_skipped_at(token.span(), reason);
+                }
+            }
+        }
+    }
+
+    // `use a::b::{c, d}`: a rule for `a::b` moves the whole group, the other rules apply
+    // to each name in it, which can only be renamed in place
+    fn replace_in_use_group(&mut self, node: &mut UseTree) {
+        let mut prefix = CanonicalPath {
+            leading_colon: false,
+            segments: vec![],
+        };
+        let mut tree = &*node;
+        while let UseTree::Path(use_path) = tree {
+            prefix.segments.push(use_path.ident.to_string());
+            tree = &use_path.tree;
+        }
+        let replacer = self.replacer;
+        if let (false, Some(replacement)) = (
+            prefix.segments.is_empty(),
+            replacer.get_import_replacement(&prefix),
+        ) {
+            let group = format!(
+                "{}::{}",
+                replacement,
+                self.visited_text(tree, node_range(tree))
+            );
+            self.replace_use_tree(node, group);
+            return;
+        }
+        let renamed = |path: &CanonicalPath, _: &str| replacer.get_import_replacement(path);
+        let prefix = CanonicalPath {
+            leading_colon: false,
+            segments: vec![],
+        };
+        self.rename_in_use_tree(node, &renamed, "item", prefix, None, false);
+    }
+}
+
+impl VisitMut for FileReplacer<'_> {
+    // The conversions between method and function calls change the kind of the expression
+    fn visit_expr_mut(&mut self, node: &mut Expr) {
+        let replaced = self.ssr_replace(
+            &*node,
+            |matcher, pattern, bindings| match pattern {
+                SsrPattern::Expr(pattern) => matcher.match_expr(pattern, node, bindings),
+                _ => false,
+            },
+            parse_replacement::<Expr>,
+        );
+        if let Some(expr) = replaced {
+            *node = expr;
+            return;
+        }
+        match node {
+            Expr::MethodCall(call) => {
+                let receiver_type = self.expr_type_hint(&call.receiver);
+                let method = call.method.to_string();
+                if let Some(function) = self
+                    .replacer
+                    .get_method_to_function(&method, receiver_type.as_deref())
+                {
+                    if let Some(expr) = self.method_call_to_function(call, &function) {
+                        *node = expr;
+                        return;
+                    }
+                }
+            }
+            Expr::Call(call) => {
+                if let Expr::Path(ExprPath { path, .. }) = &*call.func {
+                    let replacer = self.replacer;
+                    let resolved = self.scopes.resolve(&CanonicalPath::from_path(path));
+                    let template = replacer.get_call_template(path, &resolved, call.args.len());
+                    let method = replacer.get_function_to_method(path, &resolved);
+                    if self.local_name(path).is_none() {
+                        if let Some(template) = template {
+                            if let Some(expr) = self.call_from_template(call, template) {
+                                *node = expr;
+                                return;
+                            }
+                        } else if let Some(method) = method {
+                            if let Some(expr) = self.function_call_to_method(call, &method) {
+                                *node = expr;
+                                return;
+                            }
+                        } else {
+                            let arities = replacer.call_template_arities(path, &resolved);
+                            if !arities.is_empty() {
+                                let reason = format!(
+                                    "call to `{}` not rewritten, its template takes {} argument(s), not {}",
+                                    CanonicalPath::from_path(path),
+                                    arities.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(" or "),
+                                    call.args.len()
+                                );
+                                self.report_skipped(path, reason);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        syn::visit_mut::visit_expr_mut(self, node);
+    }
+
+    fn visit_stmt_mut(&mut self, node: &mut Stmt) {
+        let replaced = self.ssr_replace(
+            &*node,
+            |matcher, pattern, bindings| match pattern {
+                SsrPattern::Stmt(pattern) => matcher.match_stmt(pattern, node, bindings),
+                _ => false,
+            },
+            |text| {
+                // several statements are kept as they are, for the text only
+                let mut stmts = parse_replacement_with(Block::parse_within, text)?;
+                Ok(match stmts.len() {
+                    1 => stmts.remove(0),
+                    _ => Stmt::Expr(Expr::Verbatim(parse_replacement(text)?), None),
+                })
+            },
+        );
+        match replaced {
+            Some(stmt) => {
+                *node = stmt;
+                if let Stmt::Local(local) = node {
+                    self.locals.add_pat(&local.pat, None);
+                }
+            }
+            None => syn::visit_mut::visit_stmt_mut(self, node),
+        }
+    }
+
+    fn visit_item_mut(&mut self, node: &mut Item) {
+        let replaced = self.ssr_replace(
+            &*node,
+            |matcher, pattern, bindings| match pattern {
+                SsrPattern::Item(pattern) => matcher.match_item(pattern, node, bindings),
+                _ => false,
+            },
+            |text| {
+                let mut file = parse_replacement::<syn::File>(text)?;
+                Ok(match file.items.len() {
+                    1 => file.items.remove(0),
+                    _ => Item::Verbatim(parse_replacement(text)?),
+                })
+            },
+        );
+        match replaced {
+            Some(item) => *node = item,
+            None => syn::visit_mut::visit_item_mut(self, node),
+        }
+    }
+
+    fn visit_item_mod_mut(&mut self, node: &mut ItemMod) {
+        if let Some((_, ref items)) = node.content {
+            self.scopes.push_module(&node.ident.to_string(), items);
+            syn::visit_mut::visit_item_mod_mut(self, node);
+            self.scopes.pop();
+        } else {
+            syn::visit_mut::visit_item_mod_mut(self, node);
+        }
+    }
+
+    fn visit_item_impl_mut(&mut self, node: &mut ItemImpl) {
+        self.impl_types.push(type_name(&node.self_ty));
+        for attr in node.attrs.iter_mut() {
+            self.visit_attribute_mut(attr);
+        }
+        self.visit_generics_mut(&mut node.generics);
+        if let Some((_, path, _)) = &mut node.trait_ {
+            self.visit_path_at(path, PathPosition::Type);
+        }
+        self.visit_type_mut(&mut node.self_ty);
+        for item in node.items.iter_mut() {
+            self.visit_impl_item_mut(item);
+        }
+        self.impl_types.pop();
+    }
+
+    // The paths in type position
+    fn visit_type_path_mut(&mut self, node: &mut syn::TypePath) {
+        if let Some(qself) = &mut node.qself {
+            self.visit_qself_mut(qself);
+        }
+        self.visit_path_at(&mut node.path, PathPosition::Type);
+    }
+
+    fn visit_trait_bound_mut(&mut self, node: &mut syn::TraitBound) {
+        if let Some(lifetimes) = &mut node.lifetimes {
+            self.visit_bound_lifetimes_mut(lifetimes);
+        }
+        self.visit_path_at(&mut node.path, PathPosition::Type);
+    }
+
+    // The paths in value position: expressions, and the patterns
+    fn visit_expr_path_mut(&mut self, node: &mut ExprPath) {
+        for attr in node.attrs.iter_mut() {
+            self.visit_attribute_mut(attr);
+        }
+        if let Some(qself) = &mut node.qself {
+            self.visit_qself_mut(qself);
+        }
+        self.visit_path_at(&mut node.path, PathPosition::Value);
+    }
+
+    fn visit_expr_struct_mut(&mut self, node: &mut syn::ExprStruct) {
+        let struct_type = self.struct_type_name(&node.path);
+        for attr in node.attrs.iter_mut() {
+            self.visit_attribute_mut(attr);
+        }
+        if let Some(qself) = &mut node.qself {
+            self.visit_qself_mut(qself);
+        }
+        self.visit_path_at(&mut node.path, PathPosition::Value);
+        for field in node.fields.iter_mut() {
+            let value_range = node_range(&field.expr);
+            self.visit_field_value_mut(field);
+            self.rename_struct_member(
+                &mut field.member,
+                &mut field.colon_token,
+                struct_type.as_deref(),
+                value_range,
+            );
+        }
+        if let Some(rest) = &mut node.rest {
+            self.visit_expr_mut(rest);
+        }
+    }
+
+    fn visit_pat_struct_mut(&mut self, node: &mut syn::PatStruct) {
+        for attr in node.attrs.iter_mut() {
+            self.visit_attribute_mut(attr);
+        }
+        if let Some(qself) = &mut node.qself {
+            self.visit_qself_mut(qself);
+        }
+        let struct_type = self.struct_type_name(&node.path);
+        self.visit_path_at(&mut node.path, PathPosition::Value);
+        for field in node.fields.iter_mut() {
+            let pat_range = node_range(&field.pat);
+            self.visit_field_pat_mut(field);
+            self.rename_struct_member(
+                &mut field.member,
+                &mut field.colon_token,
+                struct_type.as_deref(),
+                pat_range,
+            );
+        }
+    }
+
+    // `expr.field`, where the type of `expr` is plain to see
+    fn visit_expr_field_mut(&mut self, node: &mut syn::ExprField) {
+        syn::visit_mut::visit_expr_field_mut(self, node);
+        let Member::Named(ident) = &node.member else {
+            return;
+        };
+        let field = ident.to_string();
+        let receiver_type = self.expr_type_hint(&node.base);
+        match self
+            .replacer
+            .get_field_rename(&field, receiver_type.as_deref())
+        {
+            Some(new_name) => match parse_replacement::<syn::Ident>(&new_name) {
+                Ok(new_ident) => {
+                    if let Some(range) = node_range(ident) {
+                        self.edits.record(range, new_name);
+                    }
+                    node.member = Member::Named(new_ident);
+                }
+                Err(_) => {
+                    let reason = format!(
+                        "field `{}` not renamed, `{}` is not a name",
+                        field, new_name
+                    );
+                    self.report_skipped_at(ident.span(), reason);
+                }
+            },
+            None if receiver_type.is_none() && self.replacer.has_typed_field_rename(&field) => {
+                let reason = format!(
+                    "field `{}` not renamed, the type of the expression is not known",
+                    field
+                );
+                self.push_skipped(ident.span(), reason);
+            }
+            None => {}
+        }
+    }
+
+    // The definition of the fields. The structs deriving the serde traits keep
+    // the old name for serialization.
+    fn visit_item_struct_mut(&mut self, node: &mut ItemStruct) {
+        syn::visit_mut::visit_item_struct_mut(self, node);
+        let struct_type = node.ident.to_string();
+        let serde = derives_serde(&node.attrs);
+        let rename_all = has_serde_arg(&node.attrs, "rename_all");
+        for field in node.fields.iter_mut() {
+            let Some(ident) = &field.ident else {
+                continue;
+            };
+            let old_name = ident.to_string();
+            let Some(new_name) = self
+                .replacer
+                .get_field_rename(&old_name, Some(&struct_type))
+            else {
+                continue;
+            };
+            let Ok(new_ident) = parse_replacement::<syn::Ident>(&new_name) else {
+                let reason = format!(
+                    "field `{}` not renamed, `{}` is not a name",
+                    old_name, new_name
+                );
+                self.report_skipped_at(ident.span(), reason);
+                continue;
+            };
+            let keep_serialized_name = serde && !has_serde_arg(&field.attrs, "rename");
+            if keep_serialized_name && rename_all {
+                let reason = format!(
+                    "field `{}` renamed, its serialized name changes with `rename_all`",
+                    old_name
+                );
+                self.push_skipped(ident.span(), reason);
+            }
+            // the attribute goes on its own line, after the other attributes
+            let insert_at = match &field.vis {
+                syn::Visibility::Inherited => node_range(ident),
+                vis => node_range(vis),
+            };
+            if let (Some(insert_at), Some(ident_range)) = (insert_at, node_range(ident)) {
+                let at = insert_at.start;
+                let line_start = self.source[..at].rfind('\n').map(|i| i + 1).unwrap_or(0);
+                let indent = &self.source[line_start..at];
+                let indent = if indent.trim().is_empty() {
+                    indent
+                } else {
+                    " "
+                };
+                let serde_attr = format!("#[serde(rename = \"{}\")]\n{}", old_name, indent);
+                if !keep_serialized_name || rename_all {
+                    self.edits.record(ident_range, new_name);
+                } else if at == ident_range.start {
+                    self.edits
+                        .record(ident_range, format!("{}{}", serde_attr, new_name));
+                } else {
+                    self.edits.record(at..at, serde_attr);
+                    self.edits.record(ident_range, new_name);
+                }
+            }
+            if keep_serialized_name && !rename_all {
+                field
+                    .attrs
+                    .push(syn::parse_quote!(#[serde(rename = #old_name)]));
+            }
+            field.ident = Some(new_ident);
+        }
+    }
+
+    fn visit_pat_tuple_struct_mut(&mut self, node: &mut syn::PatTupleStruct) {
+        for attr in node.attrs.iter_mut() {
+            self.visit_attribute_mut(attr);
+        }
+        if let Some(qself) = &mut node.qself {
+            self.visit_qself_mut(qself);
+        }
+        self.visit_path_at(&mut node.path, PathPosition::Value);
+        for elem in node.elems.iter_mut() {
+            self.visit_pat_mut(elem);
+        }
+    }
+
+    fn visit_item_fn_mut(&mut self, node: &mut ItemFn) {
+        let sig = node.sig.clone();
+        self.visit_fn_body(&sig, |this| syn::visit_mut::visit_item_fn_mut(this, node));
+    }
+
+    fn visit_impl_item_fn_mut(&mut self, node: &mut ImplItemFn) {
+        let sig = node.sig.clone();
+        self.visit_fn_body(&sig, |this| {
+            syn::visit_mut::visit_impl_item_fn_mut(this, node)
+        });
+    }
+
+    fn visit_trait_item_fn_mut(&mut self, node: &mut TraitItemFn) {
+        let sig = node.sig.clone();
+        self.visit_fn_body(&sig, |this| {
+            syn::visit_mut::visit_trait_item_fn_mut(this, node)
+        });
+    }
+
+    fn visit_expr_closure_mut(&mut self, node: &mut ExprClosure) {
+        self.locals.push();
+        for input in &node.inputs {
+            self.locals.add_pat(input, None);
+        }
+        syn::visit_mut::visit_expr_closure_mut(self, node);
+        self.locals.pop();
+    }
+
+    fn visit_local_mut(&mut self, node: &mut Local) {
+        // the bindings are visible after the initializer
+        syn::visit_mut::visit_local_mut(self, node);
+        let type_hint = node
+            .init
+            .as_ref()
+            .and_then(|init| self.expr_type_hint(&init.expr));
+        self.locals.add_pat(&node.pat, type_hint);
+    }
+
+    fn visit_arm_mut(&mut self, node: &mut Arm) {
+        self.locals.push();
+        self.locals.add_pat(&node.pat, None);
+        syn::visit_mut::visit_arm_mut(self, node);
+        self.locals.pop();
+    }
+
+    fn visit_expr_for_loop_mut(&mut self, node: &mut ExprForLoop) {
+        self.locals.push();
+        self.locals.add_pat(&node.pat, None);
+        syn::visit_mut::visit_expr_for_loop_mut(self, node);
+        self.locals.pop();
+    }
+
+    // The `let` bindings of the condition are only visible in the then-branch
+    fn visit_expr_if_mut(&mut self, node: &mut ExprIf) {
+        for attr in node.attrs.iter_mut() {
+            self.visit_attribute_mut(attr);
+        }
+        self.locals.push();
+        self.visit_expr_mut(&mut node.cond);
+        self.visit_block_mut(&mut node.then_branch);
+        self.locals.pop();
+        if let Some((_, else_branch)) = &mut node.else_branch {
+            self.visit_expr_mut(else_branch);
+        }
+    }
+
+    fn visit_expr_while_mut(&mut self, node: &mut ExprWhile) {
+        self.locals.push();
+        syn::visit_mut::visit_expr_while_mut(self, node);
+        self.locals.pop();
+    }
+
+    fn visit_expr_let_mut(&mut self, node: &mut ExprLet) {
+        syn::visit_mut::visit_expr_let_mut(self, node);
+        self.locals.add_pat(&node.pat, None);
+    }
+
+    fn visit_block_mut(&mut self, node: &mut Block) {
+        // the functions defined in the block are visible in all of it
+        self.locals.push();
+        for stmt in &node.stmts {
+            if let Stmt::Item(Item::Fn(item_fn)) = stmt {
+                self.locals.add(item_fn.sig.ident.to_string(), None);
+            }
+        }
+        // and the use items too
+        let has_items = node
+            .stmts
+            .iter()
+            .any(|stmt| matches!(stmt, Stmt::Item(Item::Use(_) | Item::Mod(_))));
+        if !has_items {
+            syn::visit_mut::visit_block_mut(self, node);
+        } else {
+            self.scopes.push_block(node);
+            syn::visit_mut::visit_block_mut(self, node);
+            self.scopes.pop();
+        }
+        self.locals.pop();
+    }
+
+    fn visit_expr_call_mut(&mut self, node: &mut ExprCall) {
+        // Remember where the callee was in the source, before the inner visit may replace it
+        let func_range = node_range(&node.func);
+
+        // First visit any inner call expressions
+        syn::visit_mut::visit_expr_call_mut(self, node);
+
+        // Check if this is a call to a function we want to replace
+        if let Expr::Path(ExprPath { path, .. }) = &mut *node.func {
+            let resolved = self.scopes.resolve(&CanonicalPath::from_path(path));
+            if let Some(replacement) = self.replacer.get_replacement(path, &resolved) {
+                if let Some(name) = self.local_name(path) {
+                    let reason = format!("call to `{}` not replaced, it is a local binding", name);
+                    self.report_local_binding(path, reason);
+                    return;
+                }
+                // Replace with the new path, keeping the generic arguments
+                let Some((new_path, text)) = self.path_with_generic_args(path, &replacement) else {
+                    return;
+                };
+                *path = new_path;
+                if let Some(range) = func_range {
+                    self.edits.record(range, text);
+                }
             }
         }
     }
+
+    fn visit_expr_method_call_mut(&mut self, node: &mut ExprMethodCall) {
+        // Remember where the method name and turbofish were, before the inner visit
+        let method_range = node_range(&node.method);
+        let turbofish_range = node.turbofish.as_ref().and_then(node_range);
+
+        syn::visit_mut::visit_expr_method_call_mut(self, node);
+
+        let receiver_type = self.expr_type_hint(&node.receiver);
+        let method = node.method.to_string();
+        let Some(replacement) =
+            self.replacer
+                .get_method_replacement(&method, receiver_type.as_deref(), &self.scopes)
+        else {
+            return;
+        };
+        let new_call = match parse_replacement::<ExprMethodCall>(&format!("x.{}()", replacement)) {
+            Ok(new_call) => new_call,
+            Err(e) => {
+                let reason = format!(
+                    "method `{}` not replaced, `{}` is not a method name: {}",
+                    method, replacement, e
+                );
+                self.report_skipped_at(node.method.span(), reason);
+                return;
+            }
+        };
+        // A turbofish in the replacement replaces the one at the call, otherwise it is kept
+        let mut range = method_range;
+        if new_call.turbofish.is_some() {
+            if let (Some(range), Some(turbofish_range)) = (&mut range, turbofish_range) {
+                range.end = turbofish_range.end;
+            }
+            node.turbofish = new_call.turbofish;
+        }
+        node.method = new_call.method;
+        if let Some(range) = range {
+            self.edits.record(range, replacement);
+        }
+    }
+
+    // Macro invocations in expressions, statements and items
+    fn visit_macro_mut(&mut self, node: &mut syn::Macro) {
+        let canonical = CanonicalPath::from_path(&node.path);
+        let resolved = self.scopes.resolve(&canonical);
+        match self.replacer.get_macro_replacement(&canonical, &resolved) {
+            Some(replacement) => match parse_replacement::<Path>(&replacement) {
+                Ok(new_path) => {
+                    // a macro imported by name keeps its short form, the import is renamed
+                    let (replacement, new_path) = match self.imported_macro_name(&node.path) {
+                        Some(name) => (
+                            name.clone(),
+                            Path::from(syn::Ident::new(&name, proc_macro2::Span::call_site())),
+                        ),
+                        None => (replacement, new_path),
+                    };
+                    if let Some(range) = node_range(&node.path) {
+                        self.edits.record(range, replacement);
+                    }
+                    node.path = new_path;
+                }
+                Err(e) => {
+                    let reason = format!(
+                        "macro `{}!` not replaced, `{}` is not a path: {}",
+                        canonical, replacement, e
+                    );
+                    self.report_skipped_at(node.path.segments[0].ident.span(), reason);
+                }
+            },
+            None => self.visit_path_mut(&mut node.path),
+        }
+
+        // The arguments are only tokens to syn, apply the rules to them where they parse as code
+        if canonical.to_string() == "macro_rules" {
+            return;
+        }
+        if let Some(mut args) = MacroArgs::parse(&node.tokens) {
+            self.locals.push();
+            args.visit_with(self);
+            self.locals.pop();
+            node.tokens = args.to_token_stream();
+        }
+    }
+
+    // Now properly handle use tree replacements
+    fn visit_use_tree_mut(&mut self, node: &mut UseTree) {
+        match node {
+            UseTree::Group(use_group) => {
+                for item in use_group.items.iter_mut() {
+                    self.visit_use_tree_mut(item);
+                }
+            }
+            UseTree::Glob(_) => {
+                // No specific replacements for glob imports
+            }
+            _ => match self.replacer.extract_use_path_str(node, "") {
+                Some(path_str) => {
+                    // the whole path, the original name for renames
+                    let path = CanonicalPath::parse(&path_str);
+                    if let Some(replacement) = self.replacer.get_import_replacement(&path) {
+                        self.replace_use_tree(node, replacement);
+                    }
+                }
+                None => self.replace_in_use_group(node),
+            },
+        }
+    }
+
     // Handle all paths, which will cover both use statements and function calls
+    fn visit_item_use_mut(&mut self, node: &mut ItemUse) {
+        let replacer = self.replacer;
+        let trait_rename =
+            |path: &CanonicalPath, _: &str| replacer.trait_renames.get(&path.to_string()).cloned();
+        let variant_rename = |path: &CanonicalPath, _: &str| {
+            replacer.variant_renames.get(&path.to_string()).cloned()
+        };
+        // the import of a macro cannot be told from the imports of the other items,
+        // unless the name is invoked as a macro
+        let macro_calls = if replacer.macro_replacements.is_empty() {
+            HashSet::new()
+        } else {
+            self.scopes.macro_calls().clone()
+        };
+        let macro_rename = |path: &CanonicalPath, name: &str| {
+            if macro_calls.contains(name) {
+                replacer.get_macro_import_replacement(path)
+            } else {
+                None
+            }
+        };
+        let rules: [(bool, ImportRename, &str); 3] = [
+            (replacer.trait_renames.is_empty(), &trait_rename, "trait"),
+            (
+                replacer.variant_renames.is_empty(),
+                &variant_rename,
+                "variant",
+            ),
+            (macro_calls.is_empty(), &macro_rename, "macro"),
+        ];
+        for (no_rules, renamed, kind) in rules {
+            if no_rules {
+                continue;
+            }
+            let prefix = CanonicalPath {
+                leading_colon: node.leading_colon.is_some(),
+                segments: vec![],
+            };
+            let range = node_range(&node.tree);
+            self.rename_in_use_tree(&mut node.tree, renamed, kind, prefix, range, false);
+        }
+        syn::visit_mut::visit_item_use_mut(self, node);
+    }
+
+    // The definition of a renamed trait, where the module of the file is known
+    fn visit_item_trait_mut(&mut self, node: &mut ItemTrait) {
+        if let Some(module_path) = self.scopes.module_path() {
+            let mut segments = module_path.clone();
+            segments.push(node.ident.to_string());
+            let path = CanonicalPath {
+                leading_colon: false,
+                segments,
+            };
+            if let Some(new) = self.replacer.trait_renames.get(&path.to_string()) {
+                let new_path = CanonicalPath::parse(new);
+                if same_parent(&path, &new_path) {
+                    let new_name = new_path.segments.last().unwrap().clone();
+                    if let Some(range) = node_range(&node.ident) {
+                        self.edits.record(range, new_name.clone());
+                    }
+                    node.ident = syn::Ident::new(&new_name, proc_macro2::Span::call_site());
+                } else {
+                    let reason = format!(
+                        "trait `{}` is moved to `{}`, its definition has to be moved by hand",
+                        path, new_path
+                    );
+                    self.report_skipped_at(node.ident.span(), reason);
+                }
+            }
+        }
+        syn::visit_mut::visit_item_trait_mut(self, node);
+    }
+
+    // A single name in a pattern is a variant, not a binding, when a variant of this name is imported
+    fn visit_pat_mut(&mut self, node: &mut syn::Pat) {
+        if let syn::Pat::Ident(pat_ident) = node {
+            if pat_ident.by_ref.is_none()
+                && pat_ident.mutability.is_none()
+                && pat_ident.subpat.is_none()
+                && !self.replacer.variant_renames.is_empty()
+            {
+                let mut path = Path::from(pat_ident.ident.clone());
+                if self.rename_variant_path(&mut path) {
+                    *node = syn::Pat::Path(ExprPath {
+                        attrs: pat_ident.attrs.clone(),
+                        qself: None,
+                        path,
+                    });
+                    return;
+                }
+            }
+        }
+        syn::visit_mut::visit_pat_mut(self, node);
+    }
+
+    // The definitions of the renamed variants. A variant moved to another enum
+    // is left for the user to move.
+    fn visit_item_enum_mut(&mut self, node: &mut syn::ItemEnum) {
+        syn::visit_mut::visit_item_enum_mut(self, node);
+        let renames = &self.replacer.variant_renames;
+        if renames.is_empty() {
+            return;
+        }
+        let module_path = self.scopes.module_path().cloned();
+        let enum_name = node.ident.to_string();
+        for variant in node.variants.iter_mut() {
+            let variant_name = variant.ident.to_string();
+            // the full path if the module is known, else the enum and variant names
+            let rule = renames.iter().find(|(key, _)| {
+                let old = CanonicalPath::parse(key);
+                let n = old.segments.len();
+                match &module_path {
+                    Some(module_path) => {
+                        let mut segments = module_path.clone();
+                        segments.push(enum_name.clone());
+                        segments.push(variant_name.clone());
+                        old.segments == segments
+                    }
+                    None => {
+                        n >= 2
+                            && old.segments[n - 2] == enum_name
+                            && old.segments[n - 1] == variant_name
+                    }
+                }
+            });
+            let Some((old, new)) = rule else {
+                continue;
+            };
+            let old = CanonicalPath::parse(old);
+            let new_path = CanonicalPath::parse(new);
+            let new_name = new_path.segments.last().cloned().unwrap_or_default();
+            if same_parent(&old, &new_path) {
+                if let Some(range) = node_range(&variant.ident) {
+                    self.edits.record(range, new_name.clone());
+                }
+                variant.ident = syn::Ident::new(&new_name, proc_macro2::Span::call_site());
+            } else {
+                let reason = format!(
+                    "variant `{}` is moved to `{}`, its definition has to be moved by hand",
+                    old, new_path
+                );
+                let span = variant.ident.span();
+                self.report_skipped_at(span, reason);
+            }
+        }
+    }
+
     fn visit_path_mut(&mut self, path: &mut Path) {
-        let path_str = path_to_string(&path.clone());
-        if let Some(replacement) = self.get_path_replacement(&path_str) {
-            // Parse the replacement string into a new Path
-            *path = syn::parse_str::<Path>(&replacement).unwrap();
+        let position = self.position.take();
+        if self.rename_variant_path(path) {
+            syn::visit_mut::visit_path_mut(self, path);
+            return;
+        }
+        if self.rename_trait_prefix(path) {
+            // the generic arguments may have paths to replace
+            syn::visit_mut::visit_path_mut(self, path);
+            return;
+        }
+        let canonical = CanonicalPath::from_path(path);
+        if let Some(replacement) = self
+            .replacer
+            .get_positional_path_replacement(&canonical, position)
+        {
+            if let Some(name) = self.local_name(path) {
+                let reason = format!("path `{}` not replaced, it is a local binding", name);
+                self.report_local_binding(path, reason);
+                return;
+            }
+            // The generic arguments go to the new path, with their own replacements
+            let range = node_range(path);
+            for segment in path.segments.iter_mut() {
+                self.visit_path_arguments_mut(&mut segment.arguments);
+            }
+            let Some((new_path, text)) = self.path_with_generic_args(path, &replacement) else {
+                return;
+            };
+            *path = new_path;
+            if let Some(range) = range {
+                self.edits.record(range, text);
+            }
         } else {
             // Continue visiting child paths
             syn::visit_mut::visit_path_mut(self, path);
@@ -502,31 +2557,125 @@ fn path_to_string(path: &Path) -> String {
 }
 */
 
-fn path_to_string(path: &syn::Path) -> String {
-    path.to_token_stream().to_string()
+// The text of each segment of a path, generic arguments included,
+// e.g. "::a::B<T, U>::c" => ["a", "B<T, U>", "c"]
+fn split_path_segments(text: &str) -> Vec<String> {
+    let text = text.trim();
+    let text = text.strip_prefix("::").unwrap_or(text);
+    let mut segments = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            // the `>` of an arrow does not close anything
+            '>' if current.ends_with('-') => {}
+            '>' | ')' | ']' if depth > 0 => depth -= 1,
+            ':' if depth == 0 && chars.peek() == Some(&':') => {
+                chars.next();
+                // a turbofish belongs to the segment before it
+                if chars.peek() == Some(&'<') {
+                    current.push_str("::");
+                } else {
+                    segments.push(current.trim().to_string());
+                    current = String::new();
+                }
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    segments.push(current.trim().to_string());
+    segments
 }
 
-fn print_function_details(function: &ItemFn) {
-    println!("Function signature: {}", function.sig.ident);
+// Whether two paths are in the same module (or enum)
+fn same_parent(a: &CanonicalPath, b: &CanonicalPath) -> bool {
+    !a.segments.is_empty()
+        && !b.segments.is_empty()
+        && a.prefix(a.segments.len() - 1) == b.prefix(b.segments.len() - 1)
+}
 
-    // Print parameters
-    println!("Parameters:");
-    for input in &function.sig.inputs {
-        println!("  {:?}", input);
-    }
+// Whether the attributes derive Serialize or Deserialize
+fn derives_serde(attrs: &[syn::Attribute]) -> bool {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("derive"))
+        .any(|attr| {
+            let tokens = attr.meta.to_token_stream().to_string();
+            tokens.contains("Serialize") || tokens.contains("Deserialize")
+        })
+}
 
-    // Print return type if specified
-    if let syn::ReturnType::Type(_, ref ty) = function.sig.output {
-        println!("Return type: {:?}", ty);
+// Whether there is a `#[serde(...)]` attribute with this argument
+fn has_serde_arg(attrs: &[syn::Attribute], arg: &str) -> bool {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serde"))
+        .any(|attr| {
+            let mut found = false;
+            let _ = attr.parse_nested_meta(|meta| {
+                found |= meta.path.is_ident(arg);
+                if meta.input.peek(syn::Token![=]) {
+                    meta.value()?.parse::<syn::Expr>()?;
+                }
+                Ok(())
+            });
+            found
+        })
+}
+
+// The new text of a path written as `written`, which refers to the trait `old` renamed to `new`
+fn renamed_trait_path(written: &CanonicalPath, old: &CanonicalPath, new: &str) -> String {
+    let new_path = CanonicalPath::parse(new);
+    let new_name = new_path.segments.last().cloned().unwrap_or_default();
+    let old_name = old.segments.last().cloned().unwrap_or_default();
+    if written == old {
+        return new_path.to_string();
+    }
+    if written.segments.len() == 1 {
+        // imported by name, the import is renamed too; unless it is imported under an alias
+        return if written.segments[0] == old_name {
+            new_name
+        } else {
+            written.to_string()
+        };
+    }
+    if same_parent(old, &new_path) {
+        // renamed in place: the path to the module still works
+        let mut renamed = written.clone();
+        *renamed.segments.last_mut().unwrap() = new_name;
+        renamed.to_string()
     } else {
-        println!("Return type: ()");
+        new_path.to_string()
     }
+}
 
-    // Print if the function is async
-    println!("Is async: {}", function.sig.asyncness.is_some());
+// Whether an expression can be the receiver of a method call without parentheses
+fn is_receiver_expr(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Path(_)
+            | Expr::Call(_)
+            | Expr::MethodCall(_)
+            | Expr::Field(_)
+            | Expr::Index(_)
+            | Expr::Paren(_)
+            | Expr::Lit(_)
+            | Expr::Macro(_)
+            | Expr::Tuple(_)
+            | Expr::Array(_)
+            | Expr::Struct(_)
+            | Expr::Block(_)
+            | Expr::Try(_)
+            | Expr::Await(_)
+    )
+}
 
-    // Print if the function is unsafe
-    println!("Is unsafe: {}", function.sig.unsafety.is_some());
+fn path_to_string(path: &syn::Path) -> String {
+    CanonicalPath::from_path(path).to_string()
 }
 
 fn add_file_function_mappings(
@@ -534,7 +2683,7 @@ fn add_file_function_mappings(
     function_prefix: &str,
     function_table: &mut HashMap<String, String>,
 ) {
-    println!(
+    eprintln!(
         "ADDING FUNCTION MAPPINGS: {} => {}",
         file_path, function_prefix
     );
@@ -542,8 +2691,7 @@ fn add_file_function_mappings(
     let file_content = match fs::read_to_string(file_path) {
         Ok(content) => content,
         Err(err) => {
-            panic!("Error reading file {}: {}", file_path, err);
-            return;
+            fail(format!("Error reading file {}: {}", file_path, err));
         }
     };
 
@@ -551,8 +2699,7 @@ fn add_file_function_mappings(
     let syntax = match parse_file(&file_content) {
         Ok(syntax) => syntax,
         Err(err) => {
-            panic!("Error parsing file {}: {}", file_path, err);
-            return;
+            fail(format!("Error parsing file {}: {}", file_path, err));
         }
     };
 
@@ -568,7 +2715,7 @@ fn add_file_function_mappings(
                         .tokens
                         .clone()
                         .into_iter()
-                        .nth(0)
+                        .next()
                         .expect("need the first token")
                         .to_string();
 
@@ -579,7 +2726,7 @@ fn add_file_function_mappings(
                             format!("{}::{}", function_prefix, &function_match);
                         function_table.insert(function_match, function_replacement);
                     } else {
-                        println!("Skip macro {}", &macro_name);
+                        eprintln!("Skip macro {}", &macro_name);
                     }
 
                     // println!("Macro: {} = {}", path_to_string(&mac.mac.path), &mac.mac.tokens.clone().into_iter().nth(0).expect("need the first token").to_string());
@@ -590,11 +2737,11 @@ fn add_file_function_mappings(
             Item::Fn(function) => {
                 // println!("Function Ident: {}", &function.sig.ident);
                 // Get the span of the function name
-                let span = function.sig.ident.span();
+                // let span = function.sig.ident.span();
 
                 // Get line and column information
-                let start = span.start();
-                let end = span.end();
+                // let start = span.start();
+                // let end = span.end();
 
                 let function_match = function.sig.ident.to_string();
                 let function_replacement = format!("{}::{}", function_prefix, &function_match);
@@ -604,27 +2751,21 @@ fn add_file_function_mappings(
                 println!("  Ends at Line: {}, Column: {}", end.line, end.column);
                 */
                 function_table.insert(function_match, function_replacement);
-
-                if function.sig.ident == "foo" {
-                    println!("Found function foo in {}", file_path);
-                    print_function_details(&function);
-                    return;
-                }
             }
             _ => {}
         }
     }
 }
 
-fn perform_replacements(file_path: &str, opts: &Opts) {
-    let file_content = std::fs::read_to_string(file_path).unwrap();
-
-    // Parse the file
-    let mut syntax = parse_file(&file_content).unwrap();
-
+fn load_replacer(opts: &Opts) -> CodeReplacer {
     // Apply modifications with our HashMap-based replacer
     let mut replacer = if let Some(ref cpath) = opts.bulk_replacement_config {
-        CodeReplacer::from_config(&cpath).expect("Could not parse the replacer config")
+        CodeReplacer::from_config(cpath).unwrap_or_else(|e| {
+            fail(format!(
+                "Could not load the replacer config {}: {}",
+                cpath, e
+            ))
+        })
     } else {
         CodeReplacer::new()
     };
@@ -638,6 +2779,11 @@ fn perform_replacements(file_path: &str, opts: &Opts) {
             .qualified_replacements
             .insert(ia.from_arg.clone(), ia.to_arg.clone());
     }
+    for ia in &opts.callsite_template {
+        let template = CallTemplate::new(&ia.from_arg, &ia.to_arg)
+            .unwrap_or_else(|e| fail(format!("Invalid call template: {}", e)));
+        replacer.call_templates.push(template);
+    }
     for ia in &opts.path_replace {
         replacer
             .crate_replacements
@@ -648,6 +2794,68 @@ fn perform_replacements(file_path: &str, opts: &Opts) {
             .specific_path_replacements
             .insert(ia.from_arg.clone(), ia.to_arg.clone());
     }
+    for ia in &opts.path_regex_replace {
+        let regex =
+            Regex::new(&ia.from_arg).unwrap_or_else(|e| fail(format!("Invalid path regex: {}", e)));
+        replacer
+            .regex_path_replacements
+            .push((regex, ia.to_arg.clone()));
+    }
+    for ia in &opts.method_replace {
+        replacer
+            .method_replacements
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.trait_method_replace {
+        replacer
+            .trait_method_replacements
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.method_to_function {
+        replacer
+            .method_to_function
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.function_to_method {
+        replacer
+            .function_to_method
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.macro_replace {
+        replacer
+            .macro_replacements
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.trait_rename {
+        replacer
+            .trait_renames
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.field_rename {
+        replacer
+            .field_renames
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.variant_rename {
+        replacer
+            .variant_renames
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.type_path_replace {
+        replacer
+            .type_path_replacements
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for ia in &opts.value_path_replace {
+        replacer
+            .value_path_replacements
+            .insert(ia.from_arg.clone(), ia.to_arg.clone());
+    }
+    for rule in &opts.ssr {
+        let rule =
+            SsrRule::from_rule(rule).unwrap_or_else(|e| fail(format!("Invalid ssr rule: {}", e)));
+        replacer.ssr_rules.push(rule);
+    }
     for ia in &opts.file_function_mappings {
         replacer
             .file_function_mappings
@@ -657,17 +2865,189 @@ fn perform_replacements(file_path: &str, opts: &Opts) {
     for (file_path, prefix) in &replacer.file_function_mappings {
         add_file_function_mappings(file_path, prefix, &mut replacer.qualified_replacements);
     }
-    println!("Loaded replacer: {:?}", &replacer);
-    syn::visit_mut::visit_file_mut(&mut replacer, &mut syntax);
+    replacer.normalize_keys();
+    // the renames are given the new full path
+    for (renames, kind) in [
+        (&replacer.trait_renames, "trait"),
+        (&replacer.variant_renames, "variant"),
+    ] {
+        for (old, new) in renames {
+            if new.trim().is_empty() || parse_replacement::<Path>(new).is_err() {
+                fail(format!(
+                    "Invalid {} rename {}={}, the new name is not a path",
+                    kind, old, new
+                ));
+            }
+        }
+    }
+    if opts.verbose > 0 {
+        eprintln!("Loaded replacer: {:?}", &replacer);
+    }
+    replacer
+}
+
+/// The result of applying the rules to one file
+struct FileChange {
+    file_path: String,
+    original_content: String,
+    modified_content: String,
+    replacement_count: usize,
+    // the matches that were left alone, with the reason
+    skipped: Vec<String>,
+    // the matches of the rules that are still in the code
+    unreplaced: usize,
+}
+
+/// `module_path` is where the file is in its crate, if known, to resolve the relative imports
+fn rewrite_file(
+    file_path: &str,
+    module_path: Option<&Vec<String>>,
+    replacer: &CodeReplacer,
+) -> Result<FileChange, String> {
+    let file_content = std::fs::read_to_string(file_path)
+        .map_err(|e| format!("Error reading file {}: {}", file_path, e))?;
+
+    // Parse the file
+    let mut syntax = parse_file(&file_content)
+        .map_err(|e| format!("Error parsing file {}: {}", file_path, e))?;
+
+    let scopes = ImportScopes::new(module_path.cloned(), &syntax.items);
+    let mut file_replacer = FileReplacer::new(replacer, scopes, parsed_text(&file_content));
+    syn::visit_mut::visit_file_mut(&mut file_replacer, &mut syntax);
+
+    // Splice the changed fragments into the original text, keeping the rest intact
+    let (modified_content, dropped) = file_replacer.edits.apply(&file_content);
+    let replacement_count = file_replacer.edits.len() - dropped.len();
+    for edit in &dropped {
+        file_replacer.report_dropped(edit);
+    }
+    let skipped = file_replacer.skipped;
+    let unreplaced = file_replacer.unreplaced;
+
+    Ok(FileChange {
+        file_path: file_path.to_string(),
+        original_content: file_content,
+        modified_content,
+        replacement_count,
+        skipped,
+        unreplaced,
+    })
+}
+
+impl FileChange {
+    fn is_modified(&self) -> bool {
+        self.modified_content != self.original_content
+    }
+}
+
+/// Returns true if any of the rules matched in the file, whether the match
+/// could be replaced or not
+fn perform_replacements(change: &FileChange, opts: &Opts) -> bool {
+    let matched = change.replacement_count > 0 || change.unreplaced > 0;
+
+    for skipped in &change.skipped {
+        eprintln!("{}:{}", change.file_path, skipped);
+    }
 
-    // Convert back to string with proper formatting
-    let modified_content = prettyplease::unparse(&syntax);
+    if opts.diff {
+        print!(
+            "{}",
+            unified_diff(
+                &change.file_path,
+                &change.original_content,
+                &change.modified_content
+            )
+        );
+    }
+    if opts.check {
+        if change.replacement_count > 0 {
+            println!(
+                "{}: {} replacement(s) would be made",
+                change.file_path, change.replacement_count
+            );
+        }
+        if change.unreplaced > 0 {
+            println!(
+                "{}: {} match(es) cannot be replaced",
+                change.file_path, change.unreplaced
+            );
+        }
+    } else if !opts.write && !opts.diff {
+        print!("{}", change.modified_content);
+    }
+    matched
+}
+
+/// Report an error of the tool itself, as opposed to a rule that matched
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2);
+}
 
-    if opts.write {
-        std::fs::write(file_path, modified_content).unwrap();
+/// Where the journal of the writes is kept: `--journal-dir`, or `.tweak-code/journal`
+/// in the directory of the project. That is the directory of `--manifest-path`, or
+/// of the package of `--crate-root`, or the current directory for plain files.
+fn journal_dir(opts: &Opts) -> std::path::PathBuf {
+    if let Some(ref dir) = opts.journal_dir {
+        return dir.into();
+    }
+    let project_dir = if let Some(ref manifest_path) = opts.manifest_path {
+        std::path::Path::new(manifest_path)
+            .parent()
+            .map(|dir| dir.to_path_buf())
+    } else if let Some(ref crate_root) = opts.crate_root {
+        // the nearest directory with a Cargo.toml, or the one of the crate root
+        let crate_dir = std::path::Path::new(crate_root).parent();
+        crate_dir.map(|crate_dir| {
+            crate_dir
+                .ancestors()
+                .find(|dir| dir.join("Cargo.toml").is_file())
+                .unwrap_or(crate_dir)
+                .to_path_buf()
+        })
     } else {
-        println!("{}", modified_content);
+        None
+    };
+    project_dir
+        .unwrap_or_default()
+        .join(".tweak-code")
+        .join("journal")
+}
+
+/// The path identifying a file, whichever way it was written
+fn file_key(file_path: &str) -> std::path::PathBuf {
+    fs::canonicalize(file_path).unwrap_or_else(|_| file_path.into())
+}
+
+/// The path of a file as the diff headers give it: relative to the current directory,
+/// so that `a/` and `b/` can be put in front of it
+fn diff_path(file_path: &str) -> String {
+    let path = std::path::Path::new(file_path);
+    if path.is_absolute() {
+        if let Ok(cwd) = std::env::current_dir().and_then(fs::canonicalize) {
+            if let Ok(relative) = file_key(file_path).strip_prefix(cwd) {
+                return relative.display().to_string();
+            }
+        }
     }
+    file_path
+        .trim_start_matches("./")
+        .trim_start_matches('/')
+        .to_string()
+}
+
+/// Unified diff between the two versions of the file, with the headers `git apply` expects.
+/// Empty if there are no changes.
+fn unified_diff(file_path: &str, old: &str, new: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    let path = diff_path(file_path);
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string()
 }
 
 fn main() {
@@ -675,12 +3055,12 @@ fn main() {
 
     // allow to load the options, so far there is no good built-in way
     let opts = if let Some(fname) = &opts.options_override {
-        if let Ok(data) = std::fs::read_to_string(&fname) {
-            let res = serde_json::from_str(&data);
-            if res.is_ok() {
-                res.unwrap()
-            } else {
-                serde_yaml::from_str(&data).unwrap()
+        if let Ok(data) = std::fs::read_to_string(fname) {
+            match serde_json::from_str(&data) {
+                Ok(res) => res,
+                Err(_) => serde_yaml::from_str(&data).unwrap_or_else(|e| {
+                    fail(format!("Could not parse the options file {}: {}", fname, e))
+                }),
             }
         } else {
             opts
@@ -690,13 +3070,146 @@ fn main() {
     };
 
     if opts.verbose > 4 {
+        // on stderr, the output may be a patch to apply
         let data = serde_json::to_string_pretty(&opts).unwrap();
-        println!("{}", data);
-        println!("===========");
+        eprintln!("{}", data);
+        eprintln!("===========");
         let data = serde_yaml::to_string(&opts).unwrap();
-        println!("{}", data);
+        eprintln!("{}", data);
+    }
+    if let Some(Command::Undo) = opts.command {
+        match journal::undo_last(&journal_dir(&opts)) {
+            Ok(restored) => {
+                for path in restored {
+                    println!("Restored {}", path);
+                }
+            }
+            Err(e) => fail(e),
+        }
+        return;
+    }
+
+    // the same file may be given as `src/x.rs` and `./src/x.rs`, or through a symlink
+    let mut seen_paths: HashSet<std::path::PathBuf> = HashSet::new();
+    let mut file_paths: Vec<String> = inputs::expand_inputs(&opts.file_path, !opts.no_ignore)
+        .unwrap_or_else(|e| fail(format!("Could not expand the input paths: {}", e)))
+        .iter()
+        .map(|f| f.display().to_string())
+        .filter(|f| seen_paths.insert(file_key(f)))
+        .collect();
+    let mut crate_roots: Vec<std::path::PathBuf> = vec![];
+    if let Some(ref crate_root) = opts.crate_root {
+        crate_roots.push(crate_root.into());
+    }
+    if let Some(ref manifest_path) = opts.manifest_path {
+        let roots = manifest::discover_target_roots(std::path::Path::new(manifest_path))
+            .unwrap_or_else(|e| {
+                fail(format!(
+                    "Could not read the cargo manifest {}: {}",
+                    manifest_path, e
+                ))
+            });
+        crate_roots.extend(roots);
+    }
+    let mut module_paths: HashMap<std::path::PathBuf, Vec<String>> = HashMap::new();
+    for crate_root in &crate_roots {
+        let files = modules::discover_crate_files(crate_root).unwrap_or_else(|e| {
+            fail(format!(
+                "Could not discover the modules of {}: {}",
+                crate_root.display(),
+                e
+            ))
+        });
+        for module_file in files {
+            let file = module_file.path.display().to_string();
+            let key = file_key(&file);
+            module_paths
+                .entry(key.clone())
+                .or_insert(module_file.module_path);
+            // targets may share modules via #[path]
+            if seen_paths.insert(key) {
+                file_paths.push(file);
+            }
+        }
+    }
+    let excludes = inputs::Excludes::new(&opts.exclude)
+        .unwrap_or_else(|e| fail(format!("Invalid exclude pattern: {}", e)));
+    file_paths.retain(|f| !excludes.is_excluded(std::path::Path::new(f)));
+    if file_paths.is_empty() {
+        return;
+    }
+    // the rewritten files printed one after the other could not be told apart
+    if file_paths.len() > 1 && !opts.write && !opts.diff && !opts.check {
+        fail(format!(
+            "{} files to transform, use --write, --diff or --check",
+            file_paths.len()
+        ));
+    }
+
+    if let Some(jobs) = opts.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+            .unwrap_or_else(|e| fail(format!("Could not set up the thread pool: {}", e)));
+    }
+
+    let replacer = load_replacer(&opts);
+    // Parse and rewrite in parallel, then report in the input order
+    let results: Vec<Result<FileChange, String>> = file_paths
+        .par_iter()
+        .map(|file_path| rewrite_file(file_path, module_paths.get(&file_key(file_path)), &replacer))
+        .collect();
+    let mut changes = vec![];
+    let mut errors = vec![];
+    for result in results {
+        match result {
+            Ok(change) => changes.push(change),
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        for e in &errors {
+            eprintln!("{}", e);
+        }
+        eprintln!("No files were changed");
+        std::process::exit(2);
+    }
+
+    let mut matched = false;
+    for change in &changes {
+        matched |= perform_replacements(change, &opts);
+    }
+
+    if opts.write && !opts.check {
+        // Only the files that did change, all at once
+        let writes: Vec<writer::FileWrite> = changes
+            .iter()
+            .filter(|change| change.is_modified())
+            .map(|change| writer::FileWrite {
+                path: &change.file_path,
+                original: &change.original_content,
+                content: &change.modified_content,
+            })
+            .collect();
+        if writes.is_empty() {
+            return;
+        }
+        let journal_path = match journal::record(&journal_dir(&opts), &writes) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("No files were changed");
+                std::process::exit(2);
+            }
+        };
+        if let Err(e) = writer::write_all(&writes) {
+            // nothing to undo
+            let _ = std::fs::remove_file(journal_path);
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
     }
-    if let Some(ref file_path) = opts.file_path {
-        perform_replacements(&file_path, &opts);
+    if opts.check && matched {
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,158 @@
+use crate::inputs;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use toml::Value;
+
+/// Find the root files of all the targets of a package, or of all the members
+/// of a workspace: lib, bins, tests, examples, benches and the build script.
+///
+/// Explicit `[lib]`, `[[bin]]`, ... sections are honored, and the usual
+/// Cargo auto-discovery is applied unless disabled with `autobins = false` and friends.
+pub fn discover_target_roots(
+    manifest_path: &Path,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let manifest = read_manifest(manifest_path)?;
+    let manifest_dir = manifest_path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .to_path_buf();
+
+    let mut roots = vec![];
+    if manifest.get("package").is_some() {
+        roots.extend(package_target_roots(&manifest, &manifest_dir));
+    }
+
+    if let Some(workspace) = manifest.get("workspace") {
+        let excluded: Vec<PathBuf> = string_array(workspace.get("exclude"))
+            .iter()
+            .map(|e| without_cur_dir(&manifest_dir.join(e)))
+            .collect();
+        for member in string_array(workspace.get("members")) {
+            let pattern = manifest_dir.join(&member);
+            let member_dirs = inputs::matching_dirs(&pattern.to_string_lossy())?;
+            if member_dirs.is_empty() {
+                eprintln!("Warning: workspace member {} not found", member);
+            }
+            for member_dir in member_dirs {
+                let member_manifest = member_dir.join("Cargo.toml");
+                if excluded.contains(&member_dir)
+                    || without_cur_dir(&member_dir) == without_cur_dir(&manifest_dir)
+                    || !member_manifest.exists()
+                {
+                    continue;
+                }
+                let manifest = read_manifest(&member_manifest)?;
+                roots.extend(package_target_roots(&manifest, &member_dir));
+            }
+        }
+    }
+
+    roots.sort();
+    roots.dedup();
+    Ok(roots)
+}
+
+// `./crates/one` as `crates/one`, the way the member directories are found
+fn without_cur_dir(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+fn read_manifest(manifest_path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(manifest_path)
+        .map_err(|e| format!("Error reading {}: {}", manifest_path.display(), e))?;
+    let manifest = toml::from_str(&content)
+        .map_err(|e| format!("Error parsing {}: {}", manifest_path.display(), e))?;
+    Ok(manifest)
+}
+
+fn string_array(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(|v| v.as_array())
+        .map(|a| {
+            a.iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn package_target_roots(manifest: &Value, package_dir: &Path) -> Vec<PathBuf> {
+    let package = manifest.get("package");
+    let auto = |key: &str| {
+        package
+            .and_then(|p| p.get(key))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true)
+    };
+    let mut roots = vec![];
+
+    // Library
+    match manifest
+        .get("lib")
+        .and_then(|lib| lib.get("path"))
+        .and_then(|p| p.as_str())
+    {
+        Some(path) => roots.push(package_dir.join(path)),
+        None => roots.push(package_dir.join("src/lib.rs")),
+    }
+
+    // Binaries, tests, examples and benches
+    let kinds = [
+        ("bin", "autobins", "src/bin"),
+        ("test", "autotests", "tests"),
+        ("example", "autoexamples", "examples"),
+        ("bench", "autobenches", "benches"),
+    ];
+    for (section, auto_key, dir) in kinds {
+        if let Some(targets) = manifest.get(section).and_then(|t| t.as_array()) {
+            for target in targets {
+                if let Some(path) = target.get("path").and_then(|p| p.as_str()) {
+                    roots.push(package_dir.join(path));
+                } else if let Some(name) = target.get("name").and_then(|n| n.as_str()) {
+                    roots.push(package_dir.join(dir).join(format!("{}.rs", name)));
+                    roots.push(package_dir.join(dir).join(name).join("main.rs"));
+                }
+            }
+        }
+        if auto(auto_key) {
+            if section == "bin" {
+                roots.push(package_dir.join("src/main.rs"));
+            }
+            roots.extend(auto_discover(&package_dir.join(dir)));
+        }
+    }
+
+    // Build script
+    match package.and_then(|p| p.get("build")) {
+        Some(Value::String(path)) => roots.push(package_dir.join(path)),
+        Some(Value::Boolean(false)) => {}
+        _ => roots.push(package_dir.join("build.rs")),
+    }
+
+    roots.retain(|r| r.is_file());
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+// `dir/*.rs` and `dir/*/main.rs`, as Cargo does for the target auto-discovery
+fn auto_discover(dir: &Path) -> Vec<PathBuf> {
+    let mut roots = vec![];
+    let Ok(entries) = fs::read_dir(dir) else {
+        return roots;
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            let main = path.join("main.rs");
+            if main.is_file() {
+                roots.push(main);
+            }
+        } else if path.extension().map(|e| e == "rs").unwrap_or(false) {
+            roots.push(path);
+        }
+    }
+    roots
+}
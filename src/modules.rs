@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use syn::{Expr, ExprLit, Item, ItemMod, Lit, Meta};
+
+/// A file of a crate, along with the path of the module it defines, e.g. `crate::foo::bar`
+#[derive(Debug, Clone)]
+pub struct ModuleFile {
+    pub path: PathBuf,
+    pub module_path: Vec<String>,
+}
+
+/// Find all the files of a crate, starting from its root (`lib.rs`, `main.rs`, ...)
+/// and following the `mod` declarations.
+///
+/// Both `foo.rs` and `foo/mod.rs` layouts are supported, as well as `#[path]` attributes.
+/// Modules whose files do not exist (e.g. generated or behind a `cfg`) are skipped with a warning.
+pub fn discover_crate_files(
+    crate_root: &Path,
+) -> Result<Vec<ModuleFile>, Box<dyn std::error::Error>> {
+    let mut files = vec![];
+    let mut seen = HashSet::new();
+    // The crate root owns its directory, same as mod.rs
+    let dir = crate_root.parent().unwrap_or(Path::new("")).to_path_buf();
+    let module_path = vec!["crate".to_string()];
+    visit_module_file(crate_root, &dir, &module_path, &mut files, &mut seen)?;
+    Ok(files)
+}
+
+fn visit_module_file(
+    file_path: &Path,
+    dir: &Path,
+    module_path: &[String],
+    files: &mut Vec<ModuleFile>,
+    seen: &mut HashSet<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let canonical = fs::canonicalize(file_path)?;
+    if !seen.insert(canonical) {
+        return Ok(());
+    }
+    files.push(ModuleFile {
+        path: file_path.to_path_buf(),
+        module_path: module_path.to_vec(),
+    });
+
+    let content = fs::read_to_string(file_path)?;
+    let syntax = syn::parse_file(&content)
+        .map_err(|e| format!("Error parsing file {}: {}", file_path.display(), e))?;
+    // `#[path]` at the top of a file is relative to the file itself, even for `foo.rs`
+    let path_dir = file_path.parent().unwrap_or(Path::new(""));
+    visit_items(
+        &syntax.items,
+        file_path,
+        dir,
+        path_dir,
+        module_path,
+        files,
+        seen,
+    )
+}
+
+// `dir` is the directory in which the child modules of these items live,
+// `path_dir` the one their `#[path]` attributes are relative to
+fn visit_items(
+    items: &[Item],
+    file_path: &Path,
+    dir: &Path,
+    path_dir: &Path,
+    module_path: &[String],
+    files: &mut Vec<ModuleFile>,
+    seen: &mut HashSet<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for item in items {
+        let Item::Mod(item_mod) = item else {
+            continue;
+        };
+        let ident = item_mod.ident.to_string();
+        let name = ident.trim_start_matches("r#");
+        let path_attr = path_attribute(item_mod);
+        let mut child_module_path = module_path.to_vec();
+        child_module_path.push(ident.clone());
+
+        if let Some((_, ref inline_items)) = item_mod.content {
+            // Inline module: its children are in a subdirectory named after it
+            let inline_dir = match path_attr {
+                Some(p) => dir.join(p),
+                None => dir.join(name),
+            };
+            visit_items(
+                inline_items,
+                file_path,
+                &inline_dir,
+                &inline_dir,
+                &child_module_path,
+                files,
+                seen,
+            )?;
+            continue;
+        }
+
+        let candidates = if let Some(p) = path_attr {
+            // Files loaded via #[path] own their directory, like mod.rs
+            let child_file = path_dir.join(p);
+            let child_dir = child_file.parent().unwrap_or(Path::new("")).to_path_buf();
+            vec![(child_file, child_dir)]
+        } else {
+            vec![
+                (dir.join(format!("{}.rs", name)), dir.join(name)),
+                (dir.join(name).join("mod.rs"), dir.join(name)),
+            ]
+        };
+
+        match candidates
+            .iter()
+            .find(|(child_file, _)| child_file.exists())
+        {
+            Some((child_file, child_dir)) => {
+                visit_module_file(child_file, child_dir, &child_module_path, files, seen)?;
+            }
+            None => {
+                let tried: Vec<String> = candidates
+                    .iter()
+                    .map(|(f, _)| f.display().to_string())
+                    .collect();
+                eprintln!(
+                    "Warning: module {} declared in {} not found at {}",
+                    name,
+                    file_path.display(),
+                    tried.join(" or ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn path_attribute(item_mod: &ItemMod) -> Option<String> {
+    item_mod.attrs.iter().find_map(|attr| match &attr.meta {
+        Meta::NameValue(nv) if nv.path.is_ident("path") => match &nv.value {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            }) => Some(s.value()),
+            _ => None,
+        },
+        _ => None,
+    })
+}
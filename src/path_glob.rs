@@ -0,0 +1,162 @@
+/// A path pattern with segment wildcards: `*` matches exactly one segment,
+/// `**` matches any number of segments (including none).
+///
+/// The same wildcards in the replacement are filled in with what they matched,
+/// in order: `legacy::*::helpers::**` => `util::helpers::**` moves everything below
+/// `legacy::<anything>::helpers` to `util::helpers`.
+#[derive(Debug, Clone)]
+pub struct PathGlob {
+    segments: Vec<String>,
+}
+
+/// What the wildcards of a pattern matched
+#[derive(Debug, Default)]
+pub struct GlobMatch {
+    stars: Vec<String>,
+    globstars: Vec<Vec<String>>,
+}
+
+impl PathGlob {
+    pub fn new(pattern: &str) -> Self {
+        PathGlob {
+            segments: pattern.split("::").map(|s| s.to_string()).collect(),
+        }
+    }
+
+    pub fn is_glob(pattern: &str) -> bool {
+        pattern.split("::").any(|s| s == "*" || s == "**")
+    }
+
+    // The number of literal segments, more specific patterns are tried first
+    pub fn specificity(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| *s != "*" && *s != "**")
+            .count()
+    }
+
+    pub fn pattern(&self) -> String {
+        self.segments.join("::")
+    }
+
+    /// Match the whole path against the pattern
+    pub fn matches(&self, path: &[String]) -> Option<GlobMatch> {
+        let mut m = GlobMatch::default();
+        if match_segments(&self.segments, path, &mut m) {
+            Some(m)
+        } else {
+            None
+        }
+    }
+}
+
+fn match_segments(pattern: &[String], path: &[String], m: &mut GlobMatch) -> bool {
+    let Some((first, rest)) = pattern.split_first() else {
+        return path.is_empty();
+    };
+    match first.as_str() {
+        "**" => {
+            // try the longest match first, then give back segments
+            for len in (0..=path.len()).rev() {
+                let mut attempt = GlobMatch {
+                    stars: m.stars.clone(),
+                    globstars: m.globstars.clone(),
+                };
+                attempt.globstars.push(path[..len].to_vec());
+                if match_segments(rest, &path[len..], &mut attempt) {
+                    *m = attempt;
+                    return true;
+                }
+            }
+            false
+        }
+        "*" => {
+            if path.is_empty() {
+                return false;
+            }
+            m.stars.push(path[0].clone());
+            match_segments(rest, &path[1..], m)
+        }
+        literal => !path.is_empty() && path[0] == literal && match_segments(rest, &path[1..], m),
+    }
+}
+
+impl GlobMatch {
+    /// Fill in the wildcards of the replacement with the matched segments
+    pub fn substitute(&self, replacement: &str) -> String {
+        let mut stars = self.stars.iter();
+        let mut globstars = self.globstars.iter();
+        let mut segments: Vec<String> = vec![];
+        for segment in replacement.split("::") {
+            match segment {
+                "*" => segments.extend(stars.next().cloned()),
+                "**" => segments.extend(globstars.next().cloned().unwrap_or_default()),
+                other => segments.push(other.to_string()),
+            }
+        }
+        segments.join("::")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(path: &str) -> Vec<String> {
+        path.split("::").map(String::from).collect()
+    }
+
+    fn replace(pattern: &str, path: &str, replacement: &str) -> Option<String> {
+        PathGlob::new(pattern)
+            .matches(&segments(path))
+            .map(|m| m.substitute(replacement))
+    }
+
+    #[test]
+    fn star_matches_exactly_one_segment() {
+        assert_eq!(
+            replace("old::*::Foo", "old::a::Foo", "new::*::Bar").as_deref(),
+            Some("new::a::Bar")
+        );
+        assert_eq!(replace("old::*::Foo", "old::Foo", "new::*"), None);
+        assert_eq!(replace("old::*::Foo", "old::a::b::Foo", "new::*"), None);
+    }
+
+    #[test]
+    fn globstar_matches_any_number_of_segments() {
+        assert_eq!(
+            replace(
+                "legacy::*::helpers::**",
+                "legacy::x::helpers::a::b",
+                "util::helpers::**"
+            )
+            .as_deref(),
+            Some("util::helpers::a::b")
+        );
+        assert_eq!(
+            replace("legacy::**", "legacy", "util::**").as_deref(),
+            Some("util")
+        );
+        assert_eq!(
+            replace("**::Foo", "a::b::Foo", "**::Bar").as_deref(),
+            Some("a::b::Bar")
+        );
+        assert_eq!(replace("legacy::**", "other::a", "util::**"), None);
+    }
+
+    #[test]
+    fn wildcards_are_substituted_in_order() {
+        assert_eq!(
+            replace("*::**::*", "a::b::c::d", "*::x::**::*").as_deref(),
+            Some("a::x::b::c::d")
+        );
+    }
+
+    #[test]
+    fn literal_segments_make_a_pattern_more_specific() {
+        assert!(PathGlob::is_glob("a::*"));
+        assert!(!PathGlob::is_glob("a::b"));
+        assert_eq!(PathGlob::new("a::*::c").specificity(), 2);
+        assert_eq!(PathGlob::new("**").specificity(), 0);
+    }
+}
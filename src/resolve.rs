@@ -0,0 +1,436 @@
+use crate::canonical::CanonicalPath;
+use proc_macro2::{Spacing, TokenStream, TokenTree};
+use std::collections::{HashMap, HashSet};
+use syn::visit::Visit;
+use syn::{Block, Item, ItemMod, Stmt, UseTree};
+
+/// The names brought into scope by the `use` items of a file, to resolve
+/// the paths at the call sites to the full paths the rules are written against.
+///
+/// There is a scope per module (the file itself and the inline modules)
+/// and per block containing items. A block sees the names of the enclosing
+/// blocks and of its module, a module does not see the names of its parent.
+pub struct ImportScopes {
+    scopes: Vec<Scope>,
+}
+
+struct Scope {
+    // a module, as opposed to a block
+    is_module: bool,
+    // e.g. ["crate", "foo", "bar"], None if it is not known where the file is in the crate
+    module_path: Option<Vec<String>>,
+    // local name => full path
+    names: HashMap<String, CanonicalPath>,
+    // the names imported from inside a group, `use a::{b, c}`
+    grouped: HashSet<String>,
+    // paths of the glob imports
+    globs: Vec<CanonicalPath>,
+    // paths imported as `_`, e.g. traits brought in for their methods
+    anonymous: Vec<CanonicalPath>,
+    // items defined here: modules, functions, types...
+    local_items: HashSet<String>,
+    // the names invoked as macros, `name!(...)`, here and in the nested blocks
+    macro_calls: HashSet<String>,
+}
+
+impl ImportScopes {
+    /// The scopes of a file, starting with its items
+    pub fn new(module_path: Option<Vec<String>>, items: &[Item]) -> Self {
+        let mut scopes = ImportScopes { scopes: vec![] };
+        scopes.push(true, module_path, items, macro_calls(items));
+        scopes
+    }
+
+    fn current(&self) -> &Scope {
+        self.scopes.last().expect("there is always the file scope")
+    }
+
+    /// Enter an inline module, `mod name { items }`
+    pub fn push_module(&mut self, name: &str, items: &[Item]) {
+        let module_path = self.current().module_path.clone().map(|mut p| {
+            p.push(name.to_string());
+            p
+        });
+        self.push(true, module_path, items, macro_calls(items));
+    }
+
+    /// Enter a block with items, keeping the module
+    pub fn push_block(&mut self, block: &Block) {
+        let module_path = self.current().module_path.clone();
+        let items: Vec<Item> = block
+            .stmts
+            .iter()
+            .filter_map(|stmt| match stmt {
+                Stmt::Item(item @ (Item::Use(_) | Item::Mod(_))) => Some(item.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut calls = MacroCalls::default();
+        calls.visit_block(block);
+        self.push(false, module_path, &items, calls.0);
+    }
+
+    /// The path of the current module, if known
+    pub fn module_path(&self) -> Option<&Vec<String>> {
+        self.current().module_path.as_ref()
+    }
+
+    // The scopes whose names are visible here, innermost first: up to the current module
+    fn visible(&self) -> impl Iterator<Item = &Scope> {
+        let module = self.scopes.iter().rposition(|s| s.is_module).unwrap_or(0);
+        self.scopes[module..].iter().rev()
+    }
+
+    pub fn pop(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    fn push(
+        &mut self,
+        is_module: bool,
+        module_path: Option<Vec<String>>,
+        items: &[Item],
+        macro_calls: HashSet<String>,
+    ) {
+        let mut scope = Scope {
+            is_module,
+            module_path,
+            names: HashMap::new(),
+            grouped: HashSet::new(),
+            globs: vec![],
+            anonymous: vec![],
+            local_items: HashSet::new(),
+            macro_calls,
+        };
+        for item in items {
+            let ident = match item {
+                Item::Mod(item) => &item.ident,
+                Item::Fn(item) => &item.sig.ident,
+                Item::Struct(item) => &item.ident,
+                Item::Enum(item) => &item.ident,
+                Item::Union(item) => &item.ident,
+                Item::Trait(item) => &item.ident,
+                Item::Type(item) => &item.ident,
+                Item::Const(item) => &item.ident,
+                Item::Static(item) => &item.ident,
+                _ => continue,
+            };
+            scope.local_items.insert(ident.to_string());
+        }
+        for item in items {
+            if let Item::Use(item_use) = item {
+                let prefix = CanonicalPath {
+                    leading_colon: item_use.leading_colon.is_some(),
+                    segments: vec![],
+                };
+                self.add_use_tree(&mut scope, &item_use.tree, prefix, false);
+            }
+        }
+        self.scopes.push(scope);
+    }
+
+    fn add_use_tree(
+        &self,
+        scope: &mut Scope,
+        tree: &UseTree,
+        mut prefix: CanonicalPath,
+        in_group: bool,
+    ) {
+        match tree {
+            UseTree::Path(use_path) => {
+                prefix.segments.push(use_path.ident.to_string());
+                self.add_use_tree(scope, &use_path.tree, prefix, in_group);
+            }
+            UseTree::Group(use_group) => {
+                for item in &use_group.items {
+                    self.add_use_tree(scope, item, prefix.clone(), true);
+                }
+            }
+            UseTree::Name(use_name) => {
+                let name = use_name.ident.to_string();
+                // `use a::b::{self}` imports `b`
+                let (local_name, full) = if name == "self" {
+                    let local_name = prefix.segments.last().cloned().unwrap_or_default();
+                    (local_name, prefix)
+                } else {
+                    prefix.segments.push(name.clone());
+                    (name, prefix)
+                };
+                let full = self.absolute_use_path(scope, &full);
+                if in_group {
+                    scope.grouped.insert(local_name.clone());
+                }
+                scope.names.insert(local_name, full);
+            }
+            UseTree::Rename(use_rename) => {
+                let name = use_rename.ident.to_string();
+                if name != "self" {
+                    prefix.segments.push(name);
+                }
+                let full = self.absolute_use_path(scope, &prefix);
+                if use_rename.rename == "_" {
+                    scope.anonymous.push(full);
+                } else {
+                    let local_name = use_rename.rename.to_string();
+                    if in_group {
+                        scope.grouped.insert(local_name.clone());
+                    }
+                    scope.names.insert(local_name, full);
+                }
+            }
+            UseTree::Glob(_) => {
+                let full = self.absolute_use_path(scope, &prefix);
+                scope.globs.push(full);
+            }
+        }
+    }
+
+    /// The path of a `use` item of the current scope, made absolute where possible
+    pub fn absolute_import(&self, path: &CanonicalPath) -> CanonicalPath {
+        self.absolute_use_path(self.current(), path)
+    }
+
+    // The path of a `use` made absolute: self/super/crate and the local modules
+    // are made relative to the crate root when the module path is known
+    fn absolute_use_path(&self, scope: &Scope, path: &CanonicalPath) -> CanonicalPath {
+        if path.leading_colon {
+            return path.clone();
+        }
+        let Some(first) = path.segments.first() else {
+            return path.clone();
+        };
+        if scope.local_items.contains(first) {
+            if let Some(module_path) = &scope.module_path {
+                let mut segments = module_path.clone();
+                segments.extend(path.segments.iter().cloned());
+                return CanonicalPath {
+                    leading_colon: false,
+                    segments,
+                };
+            }
+        }
+        relative_to_module(&scope.module_path, path).unwrap_or_else(|| path.clone())
+    }
+
+    /// The full paths the given path may refer to, innermost scope first.
+    /// A name imported via a glob import gives a candidate for each glob in scope.
+    pub fn resolve(&self, path: &CanonicalPath) -> Vec<CanonicalPath> {
+        let mut candidates = vec![];
+        if path.leading_colon || path.segments.is_empty() {
+            return candidates;
+        }
+        let scope = self.current();
+        if let Some(resolved) = relative_to_module(&scope.module_path, path) {
+            candidates.push(resolved);
+            return candidates;
+        }
+
+        let first = &path.segments[0];
+        let rest = &path.segments[1..];
+        for scope in self.visible() {
+            if let Some(full) = scope.names.get(first) {
+                let mut resolved = full.clone();
+                resolved.segments.extend(rest.iter().cloned());
+                candidates.push(resolved);
+                return candidates;
+            }
+            if scope.local_items.contains(first) {
+                if let Some(module_path) = &scope.module_path {
+                    let mut segments = module_path.clone();
+                    segments.extend(path.segments.iter().cloned());
+                    candidates.push(CanonicalPath {
+                        leading_colon: false,
+                        segments,
+                    });
+                }
+                return candidates;
+            }
+        }
+        for scope in self.visible() {
+            for glob in &scope.globs {
+                let mut resolved = glob.clone();
+                resolved.segments.extend(path.segments.iter().cloned());
+                candidates.push(resolved);
+            }
+        }
+        candidates
+    }
+
+    /// The names invoked as macros in the current scope
+    pub fn macro_calls(&self) -> &HashSet<String> {
+        &self.current().macro_calls
+    }
+
+    /// The full path of a macro imported by name (not through a glob) and visible here,
+    /// and whether it was imported from inside a group
+    pub fn imported_macro(&self, name: &str) -> Option<(&CanonicalPath, bool)> {
+        for scope in self.visible() {
+            if let Some(full) = scope.names.get(name) {
+                if !scope.macro_calls.contains(name) {
+                    return None;
+                }
+                return Some((full, scope.grouped.contains(name)));
+            }
+            if scope.local_items.contains(name) {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Whether the item with this full path is in scope: imported by name
+    /// (including `use path::Trait as _`), through a glob import of its parent,
+    /// or defined in the current module
+    pub fn is_in_scope(&self, path: &CanonicalPath) -> bool {
+        let Some((name, parent)) = path.segments.split_last() else {
+            return false;
+        };
+        let parent = CanonicalPath {
+            leading_colon: path.leading_colon,
+            segments: parent.to_vec(),
+        };
+        self.visible().any(|scope| {
+            scope.names.values().any(|full| full == path)
+                || scope.anonymous.contains(path)
+                || scope.globs.contains(&parent)
+                || (scope.local_items.contains(name)
+                    && scope
+                        .module_path
+                        .as_ref()
+                        .map(|module_path| *module_path == parent.segments)
+                        .unwrap_or(true))
+        })
+    }
+}
+
+// The names invoked as macros in these items
+fn macro_calls(items: &[Item]) -> HashSet<String> {
+    let mut calls = MacroCalls::default();
+    for item in items {
+        calls.visit_item(item);
+    }
+    calls.0
+}
+
+// Collects the macro names of a scope, leaving out the inline modules as they have
+// their own imports. The arguments of the macros are only tokens, `name!` is looked
+// for in them too.
+#[derive(Default)]
+struct MacroCalls(HashSet<String>);
+
+impl MacroCalls {
+    fn add_tokens(&mut self, tokens: &TokenStream) {
+        let tokens: Vec<TokenTree> = tokens.clone().into_iter().collect();
+        for (i, token) in tokens.iter().enumerate() {
+            match (token, tokens.get(i + 1), tokens.get(i + 2)) {
+                (
+                    TokenTree::Ident(ident),
+                    Some(TokenTree::Punct(punct)),
+                    Some(TokenTree::Group(_)),
+                ) if punct.as_char() == '!' && punct.spacing() == Spacing::Alone => {
+                    self.0.insert(ident.to_string());
+                }
+                (TokenTree::Group(group), _, _) => self.add_tokens(&group.stream()),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for MacroCalls {
+    fn visit_item_mod(&mut self, _: &'ast ItemMod) {}
+
+    fn visit_macro(&mut self, node: &'ast syn::Macro) {
+        if let Some(ident) = node.path.get_ident() {
+            self.0.insert(ident.to_string());
+        }
+        self.add_tokens(&node.tokens);
+    }
+}
+
+// Resolve `self::`, `super::` and `crate::` paths against the module path
+fn relative_to_module(
+    module_path: &Option<Vec<String>>,
+    path: &CanonicalPath,
+) -> Option<CanonicalPath> {
+    let first = path.segments.first()?;
+    if first == "crate" {
+        return Some(path.clone());
+    }
+    if first != "self" && first != "super" {
+        return None;
+    }
+    let mut segments = module_path.clone()?;
+    let mut rest = path.segments.as_slice();
+    if rest[0] == "self" {
+        rest = &rest[1..];
+    }
+    while rest.first().map(|s| s == "super").unwrap_or(false) {
+        if segments.len() > 1 {
+            segments.pop();
+        }
+        rest = &rest[1..];
+    }
+    segments.extend(rest.iter().cloned());
+    Some(CanonicalPath {
+        leading_colon: false,
+        segments,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(source: &str) -> Vec<Item> {
+        syn::parse_file(source).unwrap().items
+    }
+
+    fn resolve(scopes: &ImportScopes, path: &str) -> Vec<String> {
+        scopes
+            .resolve(&CanonicalPath::parse(path))
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn inline_modules_do_not_see_the_imports_of_their_parent() {
+        let file = items("use a::b::foo;");
+        let mut scopes = ImportScopes::new(None, &file);
+        assert_eq!(resolve(&scopes, "foo"), vec!["a::b::foo"]);
+
+        scopes.push_module("m", &items("fn k() {}"));
+        assert!(resolve(&scopes, "foo").is_empty());
+        assert!(!scopes.is_in_scope(&CanonicalPath::parse("a::b::foo")));
+        scopes.pop();
+
+        scopes.push_module("m", &items("use other::*;"));
+        assert_eq!(resolve(&scopes, "foo"), vec!["other::foo"]);
+        scopes.pop();
+    }
+
+    #[test]
+    fn blocks_see_the_imports_of_their_module() {
+        let mut scopes = ImportScopes::new(None, &items("use a::b::foo;"));
+        scopes.push_block(&syn::parse_str("{ use c::bar; }").unwrap());
+        assert_eq!(resolve(&scopes, "foo"), vec!["a::b::foo"]);
+        assert_eq!(resolve(&scopes, "bar"), vec!["c::bar"]);
+        assert!(scopes.is_in_scope(&CanonicalPath::parse("a::b::foo")));
+    }
+
+    #[test]
+    fn only_the_names_invoked_as_macros_are_imported_macros() {
+        let file = items(
+            "use a::{m, n, S}; fn f() { m!(1); vec![n!(2)]; S::new(); } mod inner { fn g() { S!(); } }",
+        );
+        let scopes = ImportScopes::new(None, &file);
+        let calls = scopes.macro_calls();
+        assert!(calls.contains("m") && calls.contains("n"));
+        assert!(!calls.contains("S"));
+        assert_eq!(scopes.imported_macro("m").unwrap().0.to_string(), "a::m");
+        assert!(scopes.imported_macro("S").is_none());
+    }
+}
@@ -0,0 +1,496 @@
+use crate::macro_args::MacroArgs;
+use crate::template::{metavar_name, metavars, with_placeholders};
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use std::collections::HashMap;
+use syn::parse::Parser;
+use syn::{Block, Expr, Item, Member, Pat, Path, PathArguments, Stmt, Type};
+
+/// A structural search-and-replace rule: a pattern and a replacement, both
+/// Rust fragments where `$name` stands for any expression, type, pattern or name.
+///
+/// The rule is kept as text, the syntax trees are not shared between the threads:
+/// each file parses the pattern again with `parse_pattern`.
+#[derive(Debug, Clone)]
+pub struct SsrRule {
+    pub pattern: String,
+    pub template: String,
+}
+
+/// The parsed pattern of a rule
+pub enum SsrPattern {
+    Expr(Expr),
+    Stmt(Stmt),
+    Item(Item),
+}
+
+/// What a metavariable matched
+#[derive(Clone)]
+pub enum Bound {
+    Expr(Expr),
+    Type(Type),
+    Pat(Pat),
+    // a name, a literal, or a bracketed group
+    Tokens(TokenTree),
+}
+
+pub type Bindings = HashMap<String, Bound>;
+
+impl SsrRule {
+    pub fn new(pattern: &str, template: &str) -> Result<Self, String> {
+        let pattern = pattern.trim().to_string();
+        let template = template.trim().to_string();
+        // the replacement is the same kind of code as the pattern
+        let placeholders = with_placeholders(&template);
+        let parsed = match parse_fragment(&pattern)? {
+            SsrPattern::Expr(_) => syn::parse_str::<Expr>(&placeholders).map(|_| ()),
+            SsrPattern::Stmt(_) => Block::parse_within.parse_str(&placeholders).map(|_| ()),
+            SsrPattern::Item(_) => syn::parse_file(&placeholders).map(|_| ()),
+        };
+        parsed.map_err(|e| format!("replacement `{}`: {}", template, e))?;
+        let params = metavars(&pattern);
+        if let Some(unbound) = metavars(&template)
+            .into_iter()
+            .find(|m| !params.contains(m))
+        {
+            return Err(format!(
+                "replacement `{}`: `${}` is not in the pattern `{}`",
+                template, unbound, pattern
+            ));
+        }
+        Ok(SsrRule { pattern, template })
+    }
+
+    /// A rule from the command line, `PATTERN ==>> REPLACEMENT`: the fragments
+    /// may contain `=` themselves, so they are not split at the first one
+    pub fn from_rule(rule: &str) -> Result<Self, String> {
+        match rule.split_once("==>>") {
+            Some((pattern, template)) => SsrRule::new(pattern, template),
+            None => Err(format!(
+                "Invalid format: '{}'. Expected format: '<pattern> ==>> <replacement>'",
+                rule
+            )),
+        }
+    }
+
+    pub fn parse_pattern(&self) -> SsrPattern {
+        parse_fragment(&self.pattern).expect("checked when the rule was made")
+    }
+}
+
+// An expression, else a single statement or item
+fn parse_fragment(text: &str) -> Result<SsrPattern, String> {
+    let placeholders = with_placeholders(text);
+    if !placeholders.ends_with(';') {
+        if let Ok(expr) = syn::parse_str::<Expr>(&placeholders) {
+            return Ok(SsrPattern::Expr(expr));
+        }
+    }
+    let mut stmts = Block::parse_within
+        .parse_str(&placeholders)
+        .map_err(|e| format!("pattern `{}`: {}", text, e))?;
+    if stmts.len() != 1 {
+        return Err(format!(
+            "pattern `{}`: must be one expression, statement or item",
+            text
+        ));
+    }
+    Ok(match stmts.remove(0) {
+        Stmt::Item(item) => SsrPattern::Item(item),
+        stmt => SsrPattern::Stmt(stmt),
+    })
+}
+
+/// Matches a pattern against the nodes of a file.
+///
+/// `paths_equal(pattern, candidate)` tells whether two paths refer to the same item,
+/// so that a pattern written with full paths also matches the imported names.
+pub struct Matcher<'p> {
+    pub paths_equal: &'p dyn Fn(&Path, &Path) -> bool,
+}
+
+fn placeholder_ident(path: &Path) -> Option<String> {
+    let ident = path.get_ident()?.to_string();
+    metavar_name(&ident).map(String::from)
+}
+
+fn tokens_text<T: ToTokens>(node: &T) -> String {
+    node.to_token_stream().to_string()
+}
+
+impl Matcher<'_> {
+    // Bind a metavariable; a second use of the same name must match the same code
+    fn bind(&self, name: String, bound: Bound, bindings: &mut Bindings) -> bool {
+        let text = |bound: &Bound| match bound {
+            Bound::Expr(expr) => tokens_text(expr),
+            Bound::Type(ty) => tokens_text(ty),
+            Bound::Pat(pat) => tokens_text(pat),
+            Bound::Tokens(tt) => tt.to_string(),
+        };
+        match bindings.get(&name) {
+            Some(previous) => text(previous) == text(&bound),
+            None => {
+                bindings.insert(name, bound);
+                true
+            }
+        }
+    }
+
+    pub fn match_expr(&self, p: &Expr, c: &Expr, b: &mut Bindings) -> bool {
+        if let Expr::Path(path) = p {
+            if path.qself.is_none() {
+                if let Some(name) = placeholder_ident(&path.path) {
+                    return self.bind(name, Bound::Expr(c.clone()), b);
+                }
+            }
+        }
+        match (p, c) {
+            (Expr::Call(p), Expr::Call(c)) => {
+                self.match_expr(&p.func, &c.func, b)
+                    && p.args.len() == c.args.len()
+                    && p.args
+                        .iter()
+                        .zip(&c.args)
+                        .all(|(p, c)| self.match_expr(p, c, b))
+            }
+            (Expr::MethodCall(p), Expr::MethodCall(c)) => {
+                self.match_expr(&p.receiver, &c.receiver, b)
+                    && self.match_ident(&p.method, &c.method, b)
+                    && match (&p.turbofish, &c.turbofish) {
+                        (None, _) => true,
+                        (Some(p), Some(c)) => self.match_tokens(tokens(p), tokens(c), b),
+                        (Some(_), None) => false,
+                    }
+                    && p.args.len() == c.args.len()
+                    && p.args
+                        .iter()
+                        .zip(&c.args)
+                        .all(|(p, c)| self.match_expr(p, c, b))
+            }
+            (Expr::Path(p), Expr::Path(c)) if p.qself.is_none() && c.qself.is_none() => {
+                self.match_path(&p.path, &c.path, b)
+            }
+            (Expr::Binary(p), Expr::Binary(c)) => {
+                tokens_text(&p.op) == tokens_text(&c.op)
+                    && self.match_expr(&p.left, &c.left, b)
+                    && self.match_expr(&p.right, &c.right, b)
+            }
+            (Expr::Unary(p), Expr::Unary(c)) => {
+                tokens_text(&p.op) == tokens_text(&c.op) && self.match_expr(&p.expr, &c.expr, b)
+            }
+            (Expr::Field(p), Expr::Field(c)) => {
+                self.match_expr(&p.base, &c.base, b)
+                    && match (&p.member, &c.member) {
+                        (Member::Named(p), Member::Named(c)) => self.match_ident(p, c, b),
+                        (p, c) => tokens_text(p) == tokens_text(c),
+                    }
+            }
+            (Expr::Index(p), Expr::Index(c)) => {
+                self.match_expr(&p.expr, &c.expr, b) && self.match_expr(&p.index, &c.index, b)
+            }
+            (Expr::Reference(p), Expr::Reference(c)) => {
+                p.mutability.is_some() == c.mutability.is_some()
+                    && self.match_expr(&p.expr, &c.expr, b)
+            }
+            (Expr::Paren(p), Expr::Paren(c)) => self.match_expr(&p.expr, &c.expr, b),
+            (Expr::Cast(p), Expr::Cast(c)) => {
+                self.match_expr(&p.expr, &c.expr, b) && self.match_type(&p.ty, &c.ty, b)
+            }
+            (Expr::Try(p), Expr::Try(c)) => self.match_expr(&p.expr, &c.expr, b),
+            (Expr::Await(p), Expr::Await(c)) => self.match_expr(&p.base, &c.base, b),
+            (Expr::Tuple(p), Expr::Tuple(c)) => {
+                p.elems.len() == c.elems.len()
+                    && p.elems
+                        .iter()
+                        .zip(&c.elems)
+                        .all(|(p, c)| self.match_expr(p, c, b))
+            }
+            (Expr::Array(p), Expr::Array(c)) => {
+                p.elems.len() == c.elems.len()
+                    && p.elems
+                        .iter()
+                        .zip(&c.elems)
+                        .all(|(p, c)| self.match_expr(p, c, b))
+            }
+            (Expr::Macro(p), Expr::Macro(c)) => self.match_macro(&p.mac, &c.mac, b),
+            _ => self.match_tokens(tokens(p), tokens(c), b),
+        }
+    }
+
+    // The arguments of the macros are matched as expressions where they parse as such
+    fn match_macro(&self, p: &syn::Macro, c: &syn::Macro, b: &mut Bindings) -> bool {
+        if !self.match_path(&p.path, &c.path, b) {
+            return false;
+        }
+        match (MacroArgs::parse(&p.tokens), MacroArgs::parse(&c.tokens)) {
+            (Some(MacroArgs::Exprs(p)), Some(MacroArgs::Exprs(c))) => {
+                p.len() == c.len() && p.iter().zip(&c).all(|(p, c)| self.match_expr(p, c, b))
+            }
+            _ => self.match_tokens(p.tokens.clone(), c.tokens.clone(), b),
+        }
+    }
+
+    fn match_ident(&self, p: &syn::Ident, c: &syn::Ident, b: &mut Bindings) -> bool {
+        match metavar_name(&p.to_string()) {
+            Some(name) => self.bind(name.to_string(), Bound::Tokens(c.clone().into()), b),
+            None => p == c,
+        }
+    }
+
+    // Segment by segment, or the same item through the imports. The generic arguments
+    // only have to match where the pattern has some.
+    fn match_path(&self, p: &Path, c: &Path, b: &mut Bindings) -> bool {
+        let has_metavars = p
+            .segments
+            .iter()
+            .any(|s| metavar_name(&s.ident.to_string()).is_some());
+        let arguments_match = |p: &PathArguments, c: &PathArguments, b: &mut Bindings| {
+            p.is_none() || self.match_tokens(tokens(p), tokens(c), b)
+        };
+        if !has_metavars && (self.paths_equal)(p, c) {
+            return match (p.segments.last(), c.segments.last()) {
+                (Some(p), Some(c)) => arguments_match(&p.arguments, &c.arguments, b),
+                _ => true,
+            };
+        }
+        p.leading_colon.is_some() == c.leading_colon.is_some()
+            && p.segments.len() == c.segments.len()
+            && p.segments.iter().zip(&c.segments).all(|(p, c)| {
+                self.match_ident(&p.ident, &c.ident, b)
+                    && arguments_match(&p.arguments, &c.arguments, b)
+            })
+    }
+
+    pub fn match_type(&self, p: &Type, c: &Type, b: &mut Bindings) -> bool {
+        match (p, c) {
+            (Type::Path(p), _) if p.qself.is_none() && placeholder_ident(&p.path).is_some() => {
+                let name = placeholder_ident(&p.path).unwrap();
+                self.bind(name, Bound::Type(c.clone()), b)
+            }
+            (Type::Path(p), Type::Path(c)) if p.qself.is_none() && c.qself.is_none() => {
+                self.match_path(&p.path, &c.path, b)
+            }
+            (Type::Reference(p), Type::Reference(c)) => {
+                p.mutability.is_some() == c.mutability.is_some()
+                    && self.match_type(&p.elem, &c.elem, b)
+            }
+            _ => self.match_tokens(tokens(p), tokens(c), b),
+        }
+    }
+
+    pub fn match_pat(&self, p: &Pat, c: &Pat, b: &mut Bindings) -> bool {
+        match (p, c) {
+            (Pat::Ident(p), _)
+                if p.by_ref.is_none()
+                    && p.mutability.is_none()
+                    && p.subpat.is_none()
+                    && metavar_name(&p.ident.to_string()).is_some() =>
+            {
+                let name = metavar_name(&p.ident.to_string()).unwrap().to_string();
+                self.bind(name, Bound::Pat(c.clone()), b)
+            }
+            (Pat::Type(p), Pat::Type(c)) => {
+                self.match_pat(&p.pat, &c.pat, b) && self.match_type(&p.ty, &c.ty, b)
+            }
+            (Pat::TupleStruct(p), Pat::TupleStruct(c)) => {
+                self.match_path(&p.path, &c.path, b)
+                    && p.elems.len() == c.elems.len()
+                    && p.elems
+                        .iter()
+                        .zip(&c.elems)
+                        .all(|(p, c)| self.match_pat(p, c, b))
+            }
+            (Pat::Reference(p), Pat::Reference(c)) => {
+                p.mutability.is_some() == c.mutability.is_some()
+                    && self.match_pat(&p.pat, &c.pat, b)
+            }
+            (Pat::Tuple(p), Pat::Tuple(c)) => {
+                p.elems.len() == c.elems.len()
+                    && p.elems
+                        .iter()
+                        .zip(&c.elems)
+                        .all(|(p, c)| self.match_pat(p, c, b))
+            }
+            _ => self.match_tokens(tokens(p), tokens(c), b),
+        }
+    }
+
+    pub fn match_stmt(&self, p: &Stmt, c: &Stmt, b: &mut Bindings) -> bool {
+        match (p, c) {
+            (Stmt::Local(p), Stmt::Local(c)) => {
+                self.match_pat(&p.pat, &c.pat, b)
+                    && match (&p.init, &c.init) {
+                        (None, None) => true,
+                        (Some(p), Some(c)) => {
+                            self.match_expr(&p.expr, &c.expr, b)
+                                && match (&p.diverge, &c.diverge) {
+                                    (None, None) => true,
+                                    (Some((_, p)), Some((_, c))) => self.match_expr(p, c, b),
+                                    _ => false,
+                                }
+                        }
+                        _ => false,
+                    }
+            }
+            (Stmt::Expr(p, p_semi), Stmt::Expr(c, c_semi)) => {
+                p_semi.is_some() == c_semi.is_some() && self.match_expr(p, c, b)
+            }
+            (Stmt::Macro(p), Stmt::Macro(c)) => {
+                p.semi_token.is_some() == c.semi_token.is_some()
+                    && self.match_macro(&p.mac, &c.mac, b)
+            }
+            (Stmt::Item(p), Stmt::Item(c)) => self.match_item(p, c, b),
+            _ => false,
+        }
+    }
+
+    pub fn match_item(&self, p: &Item, c: &Item, b: &mut Bindings) -> bool {
+        self.match_tokens(tokens(p), tokens(c), b)
+    }
+
+    // Token by token, a metavariable matches a single token tree:
+    // a name, a literal or a bracketed group
+    fn match_tokens(&self, p: TokenStream, c: TokenStream, b: &mut Bindings) -> bool {
+        let p: Vec<TokenTree> = p.into_iter().collect();
+        let c: Vec<TokenTree> = c.into_iter().collect();
+        p.len() == c.len()
+            && p.iter().zip(&c).all(|(p, c)| match (p, c) {
+                (TokenTree::Ident(ident), _) if metavar_name(&ident.to_string()).is_some() => {
+                    let name = metavar_name(&ident.to_string()).unwrap().to_string();
+                    self.bind(name, Bound::Tokens(c.clone()), b)
+                }
+                (TokenTree::Group(p), TokenTree::Group(c)) => {
+                    p.delimiter() == c.delimiter() && self.match_tokens(p.stream(), c.stream(), b)
+                }
+                (TokenTree::Ident(p), TokenTree::Ident(c)) => p == c,
+                (TokenTree::Punct(p), TokenTree::Punct(c)) => p.as_char() == c.as_char(),
+                (TokenTree::Literal(p), TokenTree::Literal(c)) => p.to_string() == c.to_string(),
+                _ => false,
+            })
+    }
+}
+
+fn tokens<T: ToTokens>(node: &T) -> TokenStream {
+    node.to_token_stream()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonical::CanonicalPath;
+
+    // `HashMap` is imported from `std::collections`
+    fn paths_equal(p: &Path, c: &Path) -> bool {
+        let (p, c) = (
+            CanonicalPath::from_path(p).to_string(),
+            CanonicalPath::from_path(c).to_string(),
+        );
+        let imported = c.strip_prefix("HashMap");
+        p == c || (imported.is_some() && imported == p.strip_prefix("std::collections::HashMap"))
+    }
+
+    // What the metavariables matched, if the expression matches the pattern
+    fn match_expr(pattern: &str, code: &str) -> Option<Vec<(String, String)>> {
+        let SsrPattern::Expr(pattern) = parse_fragment(pattern).unwrap() else {
+            panic!("{} is not an expression", pattern);
+        };
+        let code: Expr = syn::parse_str(code).unwrap();
+        let matcher = Matcher {
+            paths_equal: &paths_equal,
+        };
+        let mut bindings = Bindings::new();
+        if !matcher.match_expr(&pattern, &code, &mut bindings) {
+            return None;
+        }
+        let mut bound: Vec<(String, String)> = bindings
+            .into_iter()
+            .map(|(name, bound)| {
+                let text = match bound {
+                    Bound::Expr(expr) => tokens_text(&expr),
+                    Bound::Type(ty) => tokens_text(&ty),
+                    Bound::Pat(pat) => tokens_text(&pat),
+                    Bound::Tokens(tt) => tt.to_string(),
+                };
+                (name, text)
+            })
+            .collect();
+        bound.sort();
+        Some(bound)
+    }
+
+    fn stmt(code: &str) -> Stmt {
+        Block::parse_within.parse_str(code).unwrap().remove(0)
+    }
+
+    #[test]
+    fn a_repeated_metavariable_matches_the_same_code() {
+        assert_eq!(
+            match_expr("$a == $a", "x.len() == x.len()"),
+            Some(vec![("a".to_string(), "x . len ()".to_string())])
+        );
+        assert_eq!(match_expr("$a == $a", "x.len() == y.len()"), None);
+    }
+
+    #[test]
+    fn paths_match_through_the_imports() {
+        let pattern = "std::collections::HashMap::new()";
+        assert!(match_expr(pattern, "HashMap::new()").is_some());
+        assert!(match_expr(pattern, "std::collections::HashMap::new()").is_some());
+        assert!(match_expr(pattern, "other::HashMap::new()").is_none());
+    }
+
+    #[test]
+    fn a_turbofish_only_has_to_match_where_the_pattern_has_one() {
+        let typed = "$s.parse::<u32>()";
+        assert!(match_expr(typed, "s.parse::<u32>()").is_some());
+        assert!(match_expr(typed, "s.parse::<u64>()").is_none());
+        assert!(match_expr(typed, "s.parse()").is_none());
+        assert!(match_expr("$s.parse()", "s.parse::<u64>()").is_some());
+        assert_eq!(
+            match_expr("$s.parse::<$t>()", "s.parse::<u64>()"),
+            Some(vec![
+                ("s".to_string(), "s".to_string()),
+                ("t".to_string(), "u64".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn a_pattern_ending_with_a_semicolon_is_a_statement() {
+        assert!(matches!(parse_fragment("foo($a)"), Ok(SsrPattern::Expr(_))));
+        assert!(matches!(
+            parse_fragment("foo($a);"),
+            Ok(SsrPattern::Stmt(_))
+        ));
+        assert!(matches!(
+            parse_fragment("let $x = foo($a);"),
+            Ok(SsrPattern::Stmt(_))
+        ));
+        assert!(matches!(
+            parse_fragment("fn $f() {}"),
+            Ok(SsrPattern::Item(_))
+        ));
+        assert!(parse_fragment("foo(); bar();").is_err());
+
+        let SsrPattern::Stmt(pattern) = parse_fragment("foo($a);").unwrap() else {
+            unreachable!()
+        };
+        let matcher = Matcher {
+            paths_equal: &paths_equal,
+        };
+        assert!(matcher.match_stmt(&pattern, &stmt("foo(1);"), &mut Bindings::new()));
+        // a tail expression is not the statement
+        assert!(!matcher.match_stmt(&pattern, &stmt("foo(1)"), &mut Bindings::new()));
+    }
+
+    #[test]
+    fn the_replacement_cannot_use_metavariables_the_pattern_does_not_bind() {
+        let err = SsrRule::new("foo($a)", "bar($a, $x)").unwrap_err();
+        assert!(err.contains("`$x` is not in the pattern"), "{}", err);
+        assert!(SsrRule::new("foo($a)", "bar($a)").is_ok());
+        // the replacement must be the same kind of code as the pattern
+        assert!(SsrRule::new("foo($a);", "let x = bar($a);").is_ok());
+        assert!(SsrRule::new("foo($a)", "let x = bar($a);").is_err());
+        assert!(SsrRule::from_rule("foo($a) => bar($a)").is_err());
+    }
+}
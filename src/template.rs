@@ -0,0 +1,233 @@
+use crate::canonical::CanonicalPath;
+use syn::{Expr, ExprCall};
+
+// `$name` is not valid Rust, the metavariables are parsed as identifiers with this prefix
+const PLACEHOLDER_PREFIX: &str = "__tweak_code_metavar_";
+
+/// A piece of a template: literal text, or a metavariable
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    Text(String),
+    Metavar(String),
+}
+
+/// Split a fragment into literal text and `$name` metavariables.
+/// A `$` inside a string or char literal is kept as it is.
+pub fn pieces(text: &str) -> Vec<Piece> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pieces = vec![];
+    let mut literal = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '"' => {
+                let end = literal_end(&chars, i, '"');
+                literal.extend(&chars[i..end]);
+                i = end;
+            }
+            // a char literal, `'"'` or `'\''`, and not a lifetime like `'a`
+            '\'' if chars.get(i + 1) == Some(&'\\') || chars.get(i + 2) == Some(&'\'') => {
+                let end = literal_end(&chars, i, '\'');
+                literal.extend(&chars[i..end]);
+                i = end;
+            }
+            '$' if chars
+                .get(i + 1)
+                .map(|c| c.is_alphabetic() || *c == '_')
+                .unwrap_or(false) =>
+            {
+                let name_end = (i + 1..chars.len())
+                    .find(|&j| !(chars[j].is_alphanumeric() || chars[j] == '_'))
+                    .unwrap_or(chars.len());
+                if !literal.is_empty() {
+                    pieces.push(Piece::Text(std::mem::take(&mut literal)));
+                }
+                pieces.push(Piece::Metavar(chars[i + 1..name_end].iter().collect()));
+                i = name_end;
+            }
+            _ => {
+                literal.push(c);
+                i += 1;
+            }
+        }
+    }
+    if !literal.is_empty() {
+        pieces.push(Piece::Text(literal));
+    }
+    pieces
+}
+
+// The index after the string or char literal starting at `start`, skipping the escapes
+fn literal_end(chars: &[char], start: usize, quote: char) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            c if c == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
+/// The fragment with the metavariables replaced by placeholder identifiers, so it parses
+pub fn with_placeholders(text: &str) -> String {
+    pieces(text)
+        .into_iter()
+        .map(|piece| match piece {
+            Piece::Text(text) => text,
+            Piece::Metavar(name) => placeholder(&name),
+        })
+        .collect()
+}
+
+pub fn placeholder(name: &str) -> String {
+    format!("{}{}", PLACEHOLDER_PREFIX, name)
+}
+
+/// The name of the metavariable, if this identifier is a placeholder for one
+pub fn metavar_name(ident: &str) -> Option<&str> {
+    ident.strip_prefix(PLACEHOLDER_PREFIX)
+}
+
+/// The metavariables used in a fragment, in order of first use
+pub fn metavars(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for piece in pieces(text) {
+        if let Piece::Metavar(name) = piece {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Fill in the metavariables of a template. `bound` gives the text of each one and
+/// whether it needs parentheses where it is not a whole argument or element by itself.
+pub fn instantiate<F>(template: &str, bound: F) -> String
+where
+    F: Fn(&str) -> Option<(String, bool)>,
+{
+    let pieces = pieces(template);
+    let mut out = String::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            Piece::Text(text) => out.push_str(text),
+            Piece::Metavar(name) => match bound(name) {
+                Some((text, needs_parens)) => {
+                    let before = match i.checked_sub(1).map(|i| &pieces[i]) {
+                        Some(Piece::Text(text)) => text.trim_end().chars().last(),
+                        _ => None,
+                    };
+                    let after = match pieces.get(i + 1) {
+                        Some(Piece::Text(text)) => text.trim_start().chars().next(),
+                        _ => None,
+                    };
+                    let delimited = matches!(before, None | Some('(' | '[' | '{' | ','))
+                        && matches!(after, None | Some(')' | ']' | '}' | ',' | ';'));
+                    if needs_parens && !delimited {
+                        out.push_str(&format!("({})", text));
+                    } else {
+                        out.push_str(&text);
+                    }
+                }
+                None => {
+                    out.push('$');
+                    out.push_str(name);
+                }
+            },
+        }
+    }
+    out
+}
+
+/// A call-site template rule: `old::f($a, $b)` => `new::g($b, $a, Default::default())`.
+///
+/// The pattern is a call with a metavariable for each argument, so it matches
+/// the calls to that function with that many arguments.
+#[derive(Debug, Clone)]
+pub struct CallTemplate {
+    pub path: CanonicalPath,
+    pub params: Vec<String>,
+    pub template: String,
+}
+
+impl CallTemplate {
+    pub fn new(pattern: &str, template: &str) -> Result<Self, String> {
+        let call: ExprCall = syn::parse_str(&with_placeholders(pattern))
+            .map_err(|e| format!("call template pattern `{}`: {}", pattern, e))?;
+        let Expr::Path(func) = &*call.func else {
+            return Err(format!(
+                "call template pattern `{}`: the function must be a path",
+                pattern
+            ));
+        };
+        let mut params = vec![];
+        for arg in &call.args {
+            let name = match arg {
+                Expr::Path(arg) if arg.path.get_ident().is_some() => {
+                    metavar_name(&arg.path.get_ident().unwrap().to_string()).map(String::from)
+                }
+                _ => None,
+            };
+            match name {
+                Some(name) if !params.contains(&name) => params.push(name),
+                _ => {
+                    return Err(format!(
+                        "call template pattern `{}`: each argument must be a distinct `$name`",
+                        pattern
+                    ))
+                }
+            }
+        }
+        syn::parse_str::<Expr>(&with_placeholders(template))
+            .map_err(|e| format!("call template `{}`: {}", template, e))?;
+        if let Some(unbound) = metavars(template).into_iter().find(|m| !params.contains(m)) {
+            return Err(format!(
+                "call template `{}`: `${}` is not in the pattern `{}`",
+                template, unbound, pattern
+            ));
+        }
+        Ok(CallTemplate {
+            path: CanonicalPath::from_path(&func.path),
+            params,
+            template: template.trim().to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_in_char_literals_do_not_start_a_string() {
+        assert_eq!(
+            metavars(r#"g('"', $a, '\'', "$not_a_metavar", $b)"#),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            with_placeholders("f::<'a>($x)"),
+            format!("f::<'a>({})", placeholder("x"))
+        );
+    }
+
+    #[test]
+    fn the_code_is_put_in_parentheses_where_it_is_not_delimited() {
+        let bound = |name: &str| match name {
+            "cond" => Some(("x || y".to_string(), true)),
+            "name" => Some(("x".to_string(), false)),
+            _ => None,
+        };
+        assert_eq!(instantiate("$cond.clone()", bound), "(x || y).clone()");
+        assert_eq!(instantiate("!$cond", bound), "!(x || y)");
+        assert_eq!(instantiate("f($cond, $name)", bound), "f(x || y, x)");
+        assert_eq!(instantiate("[ $cond ]", bound), "[ x || y ]");
+        assert_eq!(instantiate("$cond", bound), "x || y");
+        assert_eq!(instantiate("$name.clone()", bound), "x.clone()");
+        // an unbound name is left as written
+        assert_eq!(instantiate("f($other)", bound), "f($other)");
+    }
+}
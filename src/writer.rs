@@ -0,0 +1,184 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A file to be written, along with the content it had when it was read
+pub struct FileWrite<'a> {
+    pub path: &'a str,
+    pub original: &'a str,
+    pub content: &'a str,
+}
+
+/// Write all the files, or none of them.
+///
+/// The new contents are first staged into temporary files next to the originals,
+/// which are then renamed over them. If staging fails, the temporary files are removed
+/// and nothing is touched; if a rename fails, the files already replaced get their
+/// original content back. A path that is a symlink gets its target replaced.
+pub fn write_all(files: &[FileWrite]) -> Result<(), String> {
+    let targets = files
+        .iter()
+        .map(|file| resolve(file.path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut staged: Vec<PathBuf> = vec![];
+    for (file, target) in files.iter().zip(targets.iter()) {
+        match stage(file, target) {
+            Ok(tmp) => staged.push(tmp),
+            Err(e) => {
+                remove_staged(&staged);
+                return Err(e);
+            }
+        }
+    }
+    replace_all(files, &targets, &staged)
+}
+
+// Rename the staged files over their targets, rolling back on the first failure
+fn replace_all(files: &[FileWrite], targets: &[PathBuf], staged: &[PathBuf]) -> Result<(), String> {
+    for (i, (file, tmp)) in files.iter().zip(staged.iter()).enumerate() {
+        if let Err(e) = fs::rename(tmp, &targets[i]) {
+            let err = format!("Error replacing {}: {}", file.path, e);
+            remove_staged(&staged[i..]);
+            return Err(match rollback(&files[..i], &targets[..i]) {
+                Ok(()) => format!("{}, all the changes were rolled back", err),
+                Err(rollback_err) => format!("{}; rollback failed: {}", err, rollback_err),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The file a path refers to, with the symlinks resolved: the one that gets replaced
+pub fn resolve(path: &str) -> Result<PathBuf, String> {
+    fs::canonicalize(path).map_err(|e| format!("Error resolving {}: {}", path, e))
+}
+
+fn staging_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.tweak-code.tmp", name))
+}
+
+fn stage(file: &FileWrite, target: &Path) -> Result<PathBuf, String> {
+    let current = fs::read_to_string(target)
+        .map_err(|e| format!("Error reading file {}: {}", file.path, e))?;
+    if current != file.original {
+        return Err(format!("{} was modified while being processed", file.path));
+    }
+    let tmp = staging_path(target);
+    fs::write(&tmp, file.content).map_err(|e| format!("Error writing {}: {}", tmp.display(), e))?;
+    // keep the permissions of the original file
+    if let Ok(metadata) = fs::metadata(target) {
+        let _ = fs::set_permissions(&tmp, metadata.permissions());
+    }
+    Ok(tmp)
+}
+
+fn remove_staged(staged: &[PathBuf]) {
+    for tmp in staged {
+        let _ = fs::remove_file(tmp);
+    }
+}
+
+// Put back the original content of the files that were already replaced
+fn rollback(files: &[FileWrite], targets: &[PathBuf]) -> Result<(), String> {
+    let mut errors = vec![];
+    for (file, target) in files.iter().zip(targets) {
+        let tmp = staging_path(target);
+        let restored = fs::write(&tmp, file.original).and_then(|_| fs::rename(&tmp, target));
+        if let Err(e) = restored {
+            let _ = fs::remove_file(&tmp);
+            errors.push(format!("{}: {}", file.path, e));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("tweak-code-writer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn a_file_modified_since_it_was_read_stops_all_the_writes() {
+        let dir = test_dir("modified");
+        let a = dir.join("a.rs").display().to_string();
+        let b = dir.join("b.rs").display().to_string();
+        fs::write(&a, "a1").unwrap();
+        fs::write(&b, "b1 edited").unwrap();
+        let files = [
+            FileWrite {
+                path: &a,
+                original: "a1",
+                content: "a2",
+            },
+            FileWrite {
+                path: &b,
+                original: "b1",
+                content: "b2",
+            },
+        ];
+        let err = write_all(&files).unwrap_err();
+        assert!(err.contains("was modified"), "{}", err);
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a1");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "b1 edited");
+        assert_eq!(file_names(&dir), vec!["a.rs", "b.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_failed_rename_rolls_back_the_files_already_replaced() {
+        let dir = test_dir("rollback");
+        let a = dir.join("a.rs").display().to_string();
+        let b = dir.join("b.rs").display().to_string();
+        fs::write(&a, "a1").unwrap();
+        fs::write(&b, "b1").unwrap();
+        let files = [
+            FileWrite {
+                path: &a,
+                original: "a1",
+                content: "a2",
+            },
+            FileWrite {
+                path: &b,
+                original: "b1",
+                content: "b2",
+            },
+        ];
+        let targets = vec![resolve(&a).unwrap(), resolve(&b).unwrap()];
+        let staged = vec![
+            stage(&files[0], &targets[0]).unwrap(),
+            stage(&files[1], &targets[1]).unwrap(),
+        ];
+        // the second rename fails
+        fs::remove_file(&staged[1]).unwrap();
+        let err = replace_all(&files, &targets, &staged).unwrap_err();
+        assert!(err.contains("rolled back"), "{}", err);
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a1");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "b1");
+        assert_eq!(file_names(&dir), vec!["a.rs", "b.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1127 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// A scratch directory with some files, removed on drop
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str, files: &[(&str, &str)]) -> Self {
+        let root = std::env::temp_dir().join(format!("tweak-code-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        Scratch(root)
+    }
+
+    // Run the tool in the scratch directory
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_in("", args)
+    }
+
+    // Run the tool in a subdirectory of the scratch directory
+    fn run_in(&self, dir: &str, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_tweak-code"))
+            .args(args)
+            .current_dir(self.0.join(dir))
+            .output()
+            .unwrap()
+    }
+
+    // The rewritten file printed by the tool
+    fn rewrite(&self, file: &str, args: &[&str]) -> String {
+        let mut all_args = vec!["-f", file];
+        all_args.extend(args);
+        let output = self.run(&all_args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn errors_exit_with_2() {
+    let scratch = Scratch::new("errors", &[("a.rs", "fn main() {}\n")]);
+    let missing_file = scratch.run(&["-f", "missing.rs"]);
+    assert_eq!(missing_file.status.code(), Some(2));
+    let missing_config = scratch.run(&["-f", "a.rs", "--bulk-replacement-config", "missing.json"]);
+    assert_eq!(missing_config.status.code(), Some(2));
+    assert!(!String::from_utf8_lossy(&missing_config.stderr).contains("panicked"));
+}
+
+#[test]
+fn unparseable_crate_root_exits_with_2() {
+    let scratch = Scratch::new("bad-crate-root", &[("src/lib.rs", "fn (\n")]);
+    let output = scratch.run(&["--crate-root", "src/lib.rs"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn same_file_written_two_ways_is_processed_once() {
+    let scratch = Scratch::new(
+        "dedupe",
+        &[
+            ("src/lib.rs", "mod x;\nfn main() { foo(); }\n"),
+            ("src/x.rs", "fn y() { foo(); }\n"),
+        ],
+    );
+    let output = scratch.run(&[
+        "--crate-root",
+        "./src/lib.rs",
+        "-f",
+        "src",
+        "-f",
+        "./src/x.rs",
+        "--callsite-replace",
+        "foo=bar",
+        "--check",
+    ]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 2, "{}", stdout);
+}
+
+#[test]
+fn options_file_path_can_be_a_single_string() {
+    let options = r#"{
+        "file_path": "a.rs",
+        "callsite_replace": [{"from_arg": "foo", "to_arg": "bar"}],
+        "callsite_qreplace": [],
+        "path_replace": [],
+        "path_qreplace": [],
+        "file_function_mappings": [],
+        "write": false,
+        "verbose": 0
+    }"#;
+    let scratch = Scratch::new(
+        "options-string",
+        &[
+            ("a.rs", "fn main() { foo(); }\n"),
+            ("options.json", options),
+        ],
+    );
+    let output = scratch.run(&["--options-override", "options.json"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "fn main() { bar(); }\n"
+    );
+}
+
+#[test]
+fn receiver_type_is_only_taken_from_new() {
+    let source = "fn f(v: Vec<u8>) {
+    let s = String::from_utf8(v);
+    s.len_old();
+    let t = String::new();
+    t.len_old();
+    let m = std::mem::take(&mut x);
+    m.len_old();
+}
+";
+    let scratch = Scratch::new("type-hint", &[("a.rs", source)]);
+    let output = scratch.rewrite("a.rs", &["--method-replace", "String::len_old=len"]);
+    assert_eq!(output, source.replace("t.len_old()", "t.len()"));
+}
+
+#[test]
+fn value_rules_only_match_whole_paths() {
+    let scratch = Scratch::new(
+        "positions",
+        &[(
+            "a.rs",
+            "fn main() {
+    let a: Foo = Foo::new();
+    let c = Foo(2);
+}
+",
+        )],
+    );
+    let args = [
+        "--value-path-replace",
+        "Foo=make_foo",
+        "--type-path-replace",
+        "Foo=FooConfig",
+    ];
+    assert_eq!(
+        scratch.rewrite("a.rs", &args),
+        "fn main() {
+    let a: FooConfig = FooConfig::new();
+    let c = make_foo(2);
+}
+"
+    );
+}
+
+#[test]
+fn diff_headers_are_relative_for_absolute_inputs() {
+    let scratch = Scratch::new("diff-absolute", &[("src/a.rs", "fn main() { foo(); }\n")]);
+    let file = scratch.0.join("src/a.rs").display().to_string();
+    let output = scratch.run(&["-f", &file, "--callsite-replace", "foo=bar", "--diff"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("--- a/src/a.rs\n+++ b/src/a.rs\n"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn file_function_mappings_print_nothing_on_stdout() {
+    let scratch = Scratch::new(
+        "function-mappings",
+        &[
+            ("defs.rs", "fn foo() {}\nfn later() {}\n"),
+            ("a.rs", "fn main() { later(); }\n"),
+        ],
+    );
+    let output = scratch.run(&[
+        "-f",
+        "a.rs",
+        "--file-function-mappings",
+        "defs.rs=defs",
+        "--diff",
+    ]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("--- a/a.rs\n"), "{}", stdout);
+    assert!(
+        stdout.contains("+fn main() { defs::later(); }"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn import_replacement_that_is_not_a_use_tree_is_reported() {
+    let scratch = Scratch::new(
+        "bad-use-tree",
+        &[
+            ("a.rs", "use old::thing::Foo;\nfn main() {}\n"),
+            (
+                "rules.json",
+                r#"{"import_replace": {"old::*::Foo": "new::{*"}}"#,
+            ),
+        ],
+    );
+    let output = scratch.run(&["-f", "a.rs", "--bulk-replacement-config", "rules.json"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "use old::thing::Foo;\nfn main() {}\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("a.rs:1:5: import not replaced"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn import_rules_apply_to_each_name_of_a_group() {
+    let source = "use old::{Foo, util::helper as h};
+use old::util::{helper, other};
+fn main() {}
+";
+    let scratch = Scratch::new(
+        "import-groups",
+        &[
+            ("a.rs", source),
+            (
+                "moved.json",
+                r#"{"import_replace": {"old::*": "new::*", "old::util": "new::tools"}}"#,
+            ),
+            (
+                "renamed.json",
+                r#"{"import_replace": {"old::util::helper": "old::util::assist", "old::util::other": "old::util::another"}}"#,
+            ),
+        ],
+    );
+    // the path before a group moves the whole group, a name cannot leave its group
+    let moved = scratch.run(&["-f", "a.rs", "--bulk-replacement-config", "moved.json"]);
+    assert_eq!(
+        String::from_utf8(moved.stdout).unwrap(),
+        source.replace("old::util::{", "new::tools::{")
+    );
+    let stderr = String::from_utf8(moved.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "a.rs:1:11: import of item `old::Foo` not renamed, \
+             it is moved to `new::Foo` from inside a group"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("a.rs:1:22: import of item `old::util::helper` not renamed"),
+        "{}",
+        stderr
+    );
+    let check = scratch.run(&[
+        "-f",
+        "a.rs",
+        "--bulk-replacement-config",
+        "moved.json",
+        "--check",
+    ]);
+    assert_eq!(check.status.code(), Some(1), "{:?}", check);
+
+    // a rename in place is done inside the group
+    let renamed = scratch.rewrite("a.rs", &["--bulk-replacement-config", "renamed.json"]);
+    assert_eq!(
+        renamed,
+        "use old::{Foo, util::assist as h};
+use old::util::{assist, another};
+fn main() {}
+"
+    );
+}
+
+#[test]
+fn if_let_bindings_do_not_shadow_in_the_else_branch() {
+    let scratch = Scratch::new(
+        "if-let-scope",
+        &[(
+            "a.rs",
+            "fn main() {
+    if let Some(foo) = x { foo() } else if let Ok(y) = z { foo() } else { foo() }
+}
+",
+        )],
+    );
+    assert_eq!(
+        scratch.rewrite("a.rs", &["--callsite-replace", "foo=bar"]),
+        "fn main() {
+    if let Some(foo) = x { foo() } else if let Ok(y) = z { bar() } else { bar() }
+}
+"
+    );
+}
+
+#[test]
+fn macros_imported_by_name_keep_their_short_form() {
+    let scratch = Scratch::new(
+        "macro-import",
+        &[(
+            "a.rs",
+            "use old_crate::mymacro;
+use old_crate::mymacro as m;
+use old_crate::{other, mymacro as grouped};
+fn main() {
+    mymacro!(1);
+    m!(2);
+    grouped!(3);
+    old_crate::mymacro!(4);
+}
+",
+        )],
+    );
+    let args = ["--macro-replace", "old_crate::mymacro=new_crate::newmacro"];
+    let output = scratch.run(&["-f", "a.rs", args[0], args[1]]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "use new_crate::newmacro;
+use new_crate::newmacro as m;
+use old_crate::{other, mymacro as grouped};
+fn main() {
+    newmacro!(1);
+    m!(2);
+    new_crate::newmacro!(3);
+    new_crate::newmacro!(4);
+}
+"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("a.rs:3:24: import of macro `old_crate::mymacro` not renamed"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn macro_rules_only_rename_the_imports_of_invoked_macros() {
+    let source = "use old_crate::mymacro;
+use old_crate::SomeStruct;
+use old_crate::{helper, other};
+fn main() {
+    mymacro!(SomeStruct::new());
+    helper(other);
+}
+";
+    let scratch = Scratch::new("macro-import-items", &[("a.rs", source)]);
+    let exact = scratch.rewrite(
+        "a.rs",
+        &[
+            "--macro-replace",
+            "old_crate::mymacro=new_crate::newmacro",
+            "--macro-replace",
+            "old_crate::SomeStruct=new_crate::SomeStruct",
+            "--macro-replace",
+            "old_crate::helper=new_crate::helper",
+        ],
+    );
+    assert_eq!(
+        exact,
+        source
+            .replace("old_crate::mymacro", "new_crate::newmacro")
+            .replace("mymacro!", "newmacro!")
+    );
+    // wildcard rules only apply to the invocations, never to the imports
+    let wildcard = scratch.rewrite("a.rs", &["--macro-replace", "old_crate::**=new_crate::**"]);
+    assert_eq!(
+        wildcard,
+        source.replace("    mymacro!", "    new_crate::mymacro!")
+    );
+}
+
+#[test]
+fn macro_arguments_are_rewritten_only_where_they_parse() {
+    let source = "fn main() {
+    let v = vec![foo(1),   foo(2)];
+    let w = vec![foo(0) ;n];
+    assert_eq!(foo(1), 2, \"{}\", foo(3));
+    weird!(foo(1) => foo  <= (2)   foo(3));
+}
+";
+    let scratch = Scratch::new("macro-args", &[("a.rs", source)]);
+    let output = scratch.rewrite("a.rs", &["--callsite-replace", "foo=bar"]);
+    let expected = source
+        .replace("vec![foo(1),   foo(2)]", "vec![bar(1),   bar(2)]")
+        .replace("vec![foo(0) ;n]", "vec![bar(0) ;n]")
+        .replace(
+            "assert_eq!(foo(1), 2, \"{}\", foo(3))",
+            "assert_eq!(bar(1), 2, \"{}\", bar(3))",
+        );
+    assert_eq!(output, expected);
+    assert!(output.contains("weird!(foo(1) => foo  <= (2)   foo(3));"));
+}
+
+#[cfg(unix)]
+#[test]
+fn writing_through_a_symlink_replaces_its_target() {
+    let scratch = Scratch::new("symlink", &[("real/a.rs", "fn main() { foo(); }\n")]);
+    std::os::unix::fs::symlink("real/a.rs", scratch.0.join("link.rs")).unwrap();
+    let output = scratch.run(&["-f", "link.rs", "--callsite-replace", "foo=bar", "-w"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(scratch.0.join("real/a.rs")).unwrap(),
+        "fn main() { bar(); }\n"
+    );
+    let link = fs::symlink_metadata(scratch.0.join("link.rs")).unwrap();
+    assert!(link.file_type().is_symlink());
+}
+
+#[cfg(unix)]
+#[test]
+fn a_write_through_a_symlink_can_be_undone() {
+    let scratch = Scratch::new("symlink-undo", &[("real/a.rs", "fn main() { foo(); }\n")]);
+    std::os::unix::fs::symlink("real/a.rs", scratch.0.join("link.rs")).unwrap();
+    let output = scratch.run(&["-f", "link.rs", "--callsite-replace", "foo=bar", "-w"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let output = scratch.run(&["undo"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(scratch.0.join("real/a.rs")).unwrap(),
+        "fn main() { foo(); }\n"
+    );
+}
+
+#[test]
+fn the_journal_is_kept_in_the_project_directory() {
+    let scratch = Scratch::new(
+        "journal-dir",
+        &[
+            (
+                "proj/Cargo.toml",
+                "[package]\nname = \"proj\"\nversion = \"0.1.0\"\n",
+            ),
+            ("proj/src/lib.rs", "fn f() { foo(); }\n"),
+            ("elsewhere/.keep", ""),
+        ],
+    );
+    let manifest = ["--manifest-path", "../proj/Cargo.toml"];
+    let args = [
+        manifest[0],
+        manifest[1],
+        "--callsite-replace",
+        "foo=bar",
+        "-w",
+    ];
+    let output = scratch.run_in("elsewhere", &args);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert!(scratch.0.join("proj/.tweak-code/journal").is_dir());
+    assert!(!scratch.0.join("elsewhere/.tweak-code").exists());
+    let output = scratch.run_in("elsewhere", &[manifest[0], manifest[1], "undo"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(scratch.0.join("proj/src/lib.rs")).unwrap(),
+        "fn f() { foo(); }\n"
+    );
+}
+
+#[test]
+fn several_files_are_not_printed_one_after_the_other() {
+    let scratch = Scratch::new(
+        "several-files",
+        &[
+            ("src/a.rs", "fn main() { foo(); }\n"),
+            ("src/b.rs", "fn x() { foo(); }\n"),
+        ],
+    );
+    let output = scratch.run(&["-f", "src", "--callsite-replace", "foo=bar"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    assert!(output.stdout.is_empty());
+    let output = scratch.run(&["-f", "src", "--callsite-replace", "foo=bar", "--diff"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+}
+
+#[test]
+fn the_output_does_not_depend_on_the_number_of_jobs() {
+    let names: Vec<String> = (0..16)
+        .map(|i| format!("f{:02}.rs", (i * 7) % 16))
+        .collect();
+    let sources: Vec<String> = (0..16)
+        .map(|i| match i % 4 {
+            0 => "fn f() { other(); }\n".to_string(),
+            1 => format!("fn f() {{ foo({}); }}\n", i),
+            2 => "fn f() {\n    let foo = || 1;\n    foo();\n}\n".to_string(),
+            _ => format!("fn f() {{ foo({}); foo::<{}>(); }}\n", i, i),
+        })
+        .collect();
+    let files: Vec<(&str, &str)> = names
+        .iter()
+        .zip(&sources)
+        .map(|(name, source)| (name.as_str(), source.as_str()))
+        .collect();
+    let scratch = Scratch::new("jobs", &files);
+    // the files in an order that is neither sorted nor the order they are written in
+    let mut inputs = vec![];
+    for name in names.iter().rev() {
+        inputs.extend(["-f", name.as_str()]);
+    }
+    for (mode, code) in [("--diff", 0), ("--check", 1)] {
+        let outputs: Vec<_> = ["1", "4"]
+            .iter()
+            .map(|jobs| {
+                let mut args = inputs.clone();
+                args.extend(["--callsite-replace", "foo=bar", mode, "-j", jobs]);
+                scratch.run(&args)
+            })
+            .collect();
+        assert_eq!(outputs[0].status.code(), Some(code), "{:?}", outputs[0]);
+        assert_eq!(outputs[0].status.code(), outputs[1].status.code());
+        assert_eq!(outputs[0].stdout, outputs[1].stdout, "{}", mode);
+        assert_eq!(outputs[0].stderr, outputs[1].stderr, "{}", mode);
+    }
+    // and the files are listed in the order they are given
+    let check = scratch.run(
+        &[
+            inputs.as_slice(),
+            &["--callsite-replace", "foo=bar", "--check", "-j", "4"],
+        ]
+        .concat(),
+    );
+    let listed: Vec<&str> = std::str::from_utf8(&check.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.split(':').next().unwrap())
+        .collect();
+    let matching: Vec<&str> = names
+        .iter()
+        .zip(&sources)
+        .rev()
+        .filter(|(_, source)| source.contains("foo(") && !source.contains("let foo"))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    assert_eq!(listed, matching);
+}
+
+#[test]
+fn check_counts_the_matches_that_are_not_replaced() {
+    let scratch = Scratch::new(
+        "check-skipped",
+        &[
+            ("group.rs", "use old::{Other, OldTrait};\n"),
+            ("field.rs", "fn f() {\n    let z = make();\n    z.x;\n}\n"),
+            ("local.rs", "fn f() {\n    let foo = || 1;\n    foo();\n}\n"),
+        ],
+    );
+    let group = scratch.run(&[
+        "-f",
+        "group.rs",
+        "--trait-rename",
+        "old::OldTrait=new::OldTrait",
+        "--check",
+    ]);
+    assert_eq!(group.status.code(), Some(1), "{:?}", group);
+    let field = scratch.run(&[
+        "-f",
+        "field.rs",
+        "--field-rename",
+        "Point::x=left",
+        "--check",
+    ]);
+    // an access of unknown type may not be a match at all: only a warning
+    assert_eq!(field.status.code(), Some(0), "{:?}", field);
+    assert_eq!(String::from_utf8(field.stdout).unwrap(), "");
+    assert!(String::from_utf8_lossy(&field.stderr)
+        .contains("field.rs:3:7: field `x` not renamed, the type of the expression is not known"));
+    let local = scratch.run(&["-f", "local.rs", "--callsite-replace", "foo=bar", "--check"]);
+    assert_eq!(local.status.code(), Some(0), "{:?}", local);
+}
+
+#[test]
+fn renames_to_an_empty_or_invalid_path_are_rejected() {
+    let scratch = Scratch::new(
+        "empty-rename",
+        &[("tr.rs", "trait OldTrait {}\nimpl OldTrait for u8 {}\n")],
+    );
+    for rule in ["crate::OldTrait=", "crate::OldTrait=a::(b"] {
+        let output = scratch.run(&["--crate-root", "tr.rs", "--trait-rename", rule]);
+        assert_eq!(output.status.code(), Some(2), "{:?}", output);
+        assert!(!String::from_utf8_lossy(&output.stderr).contains("panicked"));
+    }
+    let output = scratch.run(&["--crate-root", "tr.rs", "--variant-rename", "crate::E::A="]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+}
+
+#[test]
+fn path_attribute_in_non_mod_rs_file_is_relative_to_the_file() {
+    // each module calls its own `g`, which the rules only know by its full path
+    let call = "fn f() { self::g(); }\n";
+    let scratch = Scratch::new(
+        "path-attr",
+        &[
+            ("src/lib.rs", "mod a;\n"),
+            (
+                "src/a.rs",
+                "#[path = \"x.rs\"] mod px; mod inline { #[path = \"y.rs\"] mod py; }\n",
+            ),
+            ("src/x.rs", call),
+            ("src/a/x.rs", call),
+            ("src/a/inline/y.rs", call),
+        ],
+    );
+    let output = scratch.run(&[
+        "--crate-root",
+        "src/lib.rs",
+        "--callsite-qreplace",
+        "crate::a::px::g=h",
+        "--callsite-qreplace",
+        "crate::a::inline::py::g=h",
+        "--check",
+    ]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "src/x.rs: 1 replacement(s) would be made
+src/a/inline/y.rs: 1 replacement(s) would be made
+"
+    );
+}
+
+#[test]
+fn manifest_targets_follow_the_cargo_discovery_rules() {
+    let call = "fn f() { foo(); }\n";
+    let package = |name: &str, extra: &str| {
+        format!(
+            "[package]\nname = \"{}\"\nversion = \"0.1.0\"\n{}",
+            name, extra
+        )
+    };
+    let one = package("one", "");
+    let two = package(
+        "two",
+        "autobins = false\n\n[[bin]]\nname = \"explicit\"\npath = \"src/cli.rs\"\n",
+    );
+    let skipped = package("skipped", "");
+    let scratch = Scratch::new(
+        "manifest",
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\"]\nexclude = [\"crates/skipped\"]\n",
+            ),
+            ("crates/one/Cargo.toml", &one),
+            ("crates/one/build.rs", call),
+            ("crates/one/src/lib.rs", call),
+            ("crates/one/src/main.rs", call),
+            ("crates/one/src/bin/tool.rs", call),
+            ("crates/one/tests/it.rs", call),
+            ("crates/one/examples/ex/main.rs", call),
+            ("crates/two/Cargo.toml", &two),
+            ("crates/two/src/lib.rs", call),
+            ("crates/two/src/main.rs", call),
+            ("crates/two/src/bin/auto.rs", call),
+            ("crates/two/src/cli.rs", call),
+            ("crates/skipped/Cargo.toml", &skipped),
+            ("crates/skipped/src/lib.rs", call),
+        ],
+    );
+    let output = scratch.run(&[
+        "--manifest-path",
+        "Cargo.toml",
+        "--callsite-replace",
+        "foo=bar",
+        "--check",
+    ]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let files: Vec<&str> = stdout
+        .lines()
+        .map(|line| line.split(':').next().unwrap())
+        .collect();
+    assert_eq!(
+        files,
+        vec![
+            "crates/one/build.rs",
+            "crates/one/examples/ex/main.rs",
+            "crates/one/src/bin/tool.rs",
+            "crates/one/src/lib.rs",
+            "crates/one/src/main.rs",
+            "crates/one/tests/it.rs",
+            "crates/two/src/cli.rs",
+            "crates/two/src/lib.rs",
+        ]
+    );
+}
+
+#[test]
+fn a_manifest_path_starting_with_a_dot_finds_the_members() {
+    let package = |name: &str| format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name);
+    let call = "fn f() { foo(); }\n";
+    let scratch = Scratch::new(
+        "dot_manifest",
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\", \"./tools/cli\"]\nexclude = [\"./crates/skipped\"]\n",
+            ),
+            ("crates/one/Cargo.toml", &package("one")),
+            ("crates/one/src/lib.rs", call),
+            ("crates/skipped/Cargo.toml", &package("skipped")),
+            ("crates/skipped/src/lib.rs", call),
+            ("tools/cli/Cargo.toml", &package("cli")),
+            ("tools/cli/src/main.rs", call),
+        ],
+    );
+    let output = scratch.run(&[
+        "--manifest-path",
+        "./Cargo.toml",
+        "--callsite-replace",
+        "foo=bar",
+        "-w",
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let read = |file: &str| fs::read_to_string(scratch.0.join(file)).unwrap();
+    assert_eq!(read("crates/one/src/lib.rs"), "fn f() { bar(); }\n");
+    assert_eq!(read("tools/cli/src/main.rs"), "fn f() { bar(); }\n");
+    assert_eq!(read("crates/skipped/src/lib.rs"), call);
+}
+
+#[test]
+fn undo_refuses_changed_files_then_goes_back_one_run_at_a_time() {
+    let scratch = Scratch::new("undo", &[("a.rs", "fn main() { foo(); }\n")]);
+    let file = scratch.0.join("a.rs");
+    for rule in ["foo=bar", "bar=baz"] {
+        let output = scratch.run(&["-f", "a.rs", "--callsite-replace", rule, "-w"]);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    }
+    fs::write(&file, "fn main() { edited(); }\n").unwrap();
+    let refused = scratch.run(&["undo"]);
+    assert_eq!(refused.status.code(), Some(2), "{:?}", refused);
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("Refusing to undo"));
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "fn main() { edited(); }\n"
+    );
+
+    fs::write(&file, "fn main() { baz(); }\n").unwrap();
+    for expected in ["fn main() { bar(); }\n", "fn main() { foo(); }\n"] {
+        let output = scratch.run(&["undo"]);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+        assert_eq!(fs::read_to_string(&file).unwrap(), expected);
+    }
+    let nothing_left = scratch.run(&["undo"]);
+    assert_eq!(nothing_left.status.code(), Some(2), "{:?}", nothing_left);
+}
+
+#[test]
+fn regex_rules_substitute_the_captures() {
+    let source = "fn main() {
+    old_api::v1::fetch(1);
+    old_api::v2::store(2);
+    other::v1::fetch(3);
+}
+";
+    let scratch = Scratch::new("regex", &[("a.rs", source)]);
+    let rule = r"^old_api::(v\d)::(?P<name>\w+)$=new_api::$1::${name}_async";
+    assert_eq!(
+        scratch.rewrite("a.rs", &["--path-regex-replace", rule]),
+        "fn main() {
+    new_api::v1::fetch_async(1);
+    new_api::v2::store_async(2);
+    other::v1::fetch(3);
+}
+"
+    );
+}
+
+#[test]
+fn call_templates_reorder_the_arguments_of_the_calls_that_fit() {
+    let source = "use old::f;
+fn main() {
+    f(a + 1, g(b));
+    old::f(x, y);
+    f(only_one);
+}
+";
+    let scratch = Scratch::new(
+        "template",
+        &[
+            ("a.rs", source),
+            ("one.rs", "use old::f;\nfn main() {\n    f(only_one);\n}\n"),
+        ],
+    );
+    let rule = "old::f($a, $b)=new::g($b, $a.clone(), Default::default())";
+    let output = scratch.run(&["-f", "a.rs", "--callsite-template", rule]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "use old::f;
+fn main() {
+    new::g(g(b), (a + 1).clone(), Default::default());
+    new::g(y, x.clone(), Default::default());
+    f(only_one);
+}
+"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "a.rs:5:5: call to `f` not rewritten, its template takes 2 argument(s), not 1"
+        ),
+        "{}",
+        stderr
+    );
+    // the call left alone is still a match
+    let check = scratch.run(&["-f", "one.rs", "--callsite-template", rule, "--check"]);
+    assert_eq!(check.status.code(), Some(1), "{:?}", check);
+    assert_eq!(
+        String::from_utf8(check.stdout).unwrap(),
+        "one.rs: 1 match(es) cannot be replaced\n"
+    );
+
+    for bad in ["old::f($a, $a)=new::g($a)", "old::f($a)=new::g($a, $b)"] {
+        let output = scratch.run(&["-f", "a.rs", "--callsite-template", bad]);
+        assert_eq!(output.status.code(), Some(2), "{:?}", output);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid call template"));
+    }
+    assert_eq!(fs::read_to_string(scratch.0.join("a.rs")).unwrap(), source);
+}
+
+#[test]
+fn method_and_function_calls_are_converted_into_each_other() {
+    let scratch = Scratch::new(
+        "ufcs",
+        &[(
+            "a.rs",
+            "use util::count;
+fn f(v: Vec<u8>, s: String) {
+    v.len_of();
+    s.len_of();
+    count(&v, 1);
+    util::count::<u8>(a + b, 2);
+    util::count();
+}
+",
+        )],
+    );
+    let args = [
+        "--method-to-function",
+        "Vec::len_of=&util::len",
+        "--function-to-method",
+        "util::count=count_in",
+    ];
+    assert_eq!(
+        scratch.rewrite("a.rs", &args),
+        "use util::count;
+fn f(v: Vec<u8>, s: String) {
+    util::len(&v);
+    s.len_of();
+    v.count_in(1);
+    (a + b).count_in::<u8>(2);
+    util::count();
+}
+"
+    );
+}
+
+#[test]
+fn traits_are_renamed_in_bounds_impls_paths_and_imports() {
+    let scratch = Scratch::new(
+        "trait-rename",
+        &[(
+            "a.rs",
+            "use old::Reader;
+use old::{Other, Reader as R};
+fn f<T: Reader<Item = u8>>(t: &dyn Reader<Item = u8>) -> impl Reader
+where
+    T: Clone + old::Reader,
+{
+    <T as Reader>::Item::default();
+    Reader::read(t);
+}
+impl Reader for u8 {}
+impl R for u16 {}
+",
+        )],
+    );
+    assert_eq!(
+        scratch.rewrite("a.rs", &["--trait-rename", "old::Reader=old::Source"]),
+        "use old::Source;
+use old::{Other, Source as R};
+fn f<T: Source<Item = u8>>(t: &dyn Source<Item = u8>) -> impl Source
+where
+    T: Clone + old::Source,
+{
+    <T as Source>::Item::default();
+    Source::read(t);
+}
+impl Source for u8 {}
+impl R for u16 {}
+"
+    );
+
+    // the definition is renamed in place, but not moved to another module
+    let defined = Scratch::new(
+        "trait-move",
+        &[("lib.rs", "pub trait Reader {}\nimpl Reader for u8 {}\n")],
+    );
+    let renamed = defined.run(&[
+        "--crate-root",
+        "lib.rs",
+        "--trait-rename",
+        "crate::Reader=crate::Source",
+    ]);
+    assert_eq!(renamed.status.code(), Some(0), "{:?}", renamed);
+    assert_eq!(
+        String::from_utf8(renamed.stdout).unwrap(),
+        "pub trait Source {}\nimpl Source for u8 {}\n"
+    );
+    let moved = defined.run(&[
+        "--crate-root",
+        "lib.rs",
+        "--trait-rename",
+        "crate::Reader=crate::io::Reader",
+        "--check",
+    ]);
+    assert_eq!(moved.status.code(), Some(1), "{:?}", moved);
+    assert!(String::from_utf8_lossy(&moved.stderr).contains(
+        "lib.rs:1:11: trait `crate::Reader` is moved to `crate::io::Reader`, \
+         its definition has to be moved by hand"
+    ));
+}
+
+#[test]
+fn fields_are_renamed_in_definitions_literals_patterns_and_accesses() {
+    let scratch = Scratch::new(
+        "field-rename",
+        &[(
+            "a.rs",
+            "#[derive(Serialize)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+impl Point {
+    fn get(&self) -> i32 {
+        self.x
+    }
+}
+fn f(p: Point, x: i32, other: Other) {
+    let q = Point { x, y: 2 };
+    let Point { x: a, .. } = q;
+    p.x + other.x
+}
+",
+        )],
+    );
+    assert_eq!(
+        scratch.rewrite("a.rs", &["--field-rename", "Point::x=left"]),
+        "#[derive(Serialize)]
+struct Point {
+    #[serde(rename = \"x\")]
+    left: i32,
+    y: i32,
+}
+impl Point {
+    fn get(&self) -> i32 {
+        self.left
+    }
+}
+fn f(p: Point, x: i32, other: Other) {
+    let q = Point { left: x, y: 2 };
+    let Point { left: a, .. } = q;
+    p.left + other.x
+}
+"
+    );
+}
+
+#[test]
+fn variants_are_renamed_or_moved_where_they_are_referred_to() {
+    let source = "use shapes::Shape::{self, Circle};
+fn f(s: Shape) {
+    match s {
+        Shape::Circle(r) => {}
+        Circle(r) => {}
+        Shape::Square(_) => {}
+    }
+    let c = shapes::Shape::Circle(1.0);
+    if let Shape::Circle(_) = c {}
+}
+";
+    let scratch = Scratch::new("variant-rename", &[("a.rs", source)]);
+    let renamed = scratch.rewrite(
+        "a.rs",
+        &[
+            "--variant-rename",
+            "shapes::Shape::Circle=shapes::Shape::Round",
+        ],
+    );
+    assert_eq!(
+        renamed,
+        source
+            .replace("Circle}", "Round}")
+            .replace("Circle(", "Round(")
+    );
+    let moved = scratch.run(&[
+        "-f",
+        "a.rs",
+        "--variant-rename",
+        "shapes::Shape::Circle=shapes::Round::Circle",
+    ]);
+    assert_eq!(moved.status.code(), Some(0), "{:?}", moved);
+    assert_eq!(
+        String::from_utf8(moved.stdout).unwrap(),
+        "use shapes::Shape::{self, Circle};
+fn f(s: Shape) {
+    match s {
+        shapes::Round::Circle(r) => {}
+        shapes::Round::Circle(r) => {}
+        Shape::Square(_) => {}
+    }
+    let c = shapes::Round::Circle(1.0);
+    if let shapes::Round::Circle(_) = c {}
+}
+"
+    );
+    // the import in the group cannot follow
+    assert!(String::from_utf8(moved.stderr)
+        .unwrap()
+        .contains("a.rs:1:27: import of variant `shapes::Shape::Circle` not renamed"));
+}
+
+#[test]
+fn generic_arguments_are_carried_over_to_the_new_path() {
+    let source = "fn f(s: &str) {
+    let v = Vec::<u8>::with_capacity(8);
+    let n = old_parse::<u32>(s);
+}
+";
+    let scratch = Scratch::new("generic-args", &[("a.rs", source)]);
+    let carried = scratch.rewrite(
+        "a.rs",
+        &[
+            "--callsite-qreplace",
+            "Vec::with_capacity=smallvec::SmallVec::with_capacity",
+            "--callsite-replace",
+            "old_parse=str_parse",
+        ],
+    );
+    assert_eq!(
+        carried,
+        "fn f(s: &str) {
+    let v = smallvec::SmallVec::<u8>::with_capacity(8);
+    let n = str_parse::<u32>(s);
+}
+"
+    );
+    // the ones written in the replacement take precedence
+    let written = scratch.rewrite(
+        "a.rs",
+        &[
+            "--path-qreplace",
+            "Vec::with_capacity=smallvec::SmallVec::<[u8; 4]>::with_capacity",
+        ],
+    );
+    assert_eq!(
+        written,
+        source.replace("Vec::<u8>", "smallvec::SmallVec::<[u8; 4]>")
+    );
+    // a shorter path has nowhere to put the arguments of `Vec`
+    let dropped = scratch.run(&[
+        "-f",
+        "a.rs",
+        "--callsite-qreplace",
+        "Vec::with_capacity=make_vec",
+    ]);
+    assert_eq!(dropped.status.code(), Some(0), "{:?}", dropped);
+    assert_eq!(String::from_utf8(dropped.stdout).unwrap(), source);
+    assert!(String::from_utf8_lossy(&dropped.stderr).contains(
+        "a.rs:2:13: path `Vec::with_capacity` not replaced, \
+         `make_vec` has no segment for the generic arguments of `Vec`"
+    ));
+}
+
+#[test]
+fn ssr_rules_rewrite_the_matched_code() {
+    let scratch = Scratch::new(
+        "ssr",
+        &[(
+            "a.rs",
+            "use std::collections::HashMap;
+fn f(a: Option<u8>, b: bool, c: bool) {
+    let m = HashMap::new();
+    let y = old::check(b || c).unwrap_or(Default::default());
+    let z = take(b || c);
+    if b == b {}
+}
+",
+        )],
+    );
+    let args = [
+        "--ssr",
+        "$e.unwrap_or(Default::default()) ==>> $e.unwrap_or_default()",
+        "--ssr",
+        "std::collections::HashMap::new() ==>> HashMap::with_capacity(16)",
+        "--ssr",
+        "take($v) ==>> $v.clone()",
+        "--ssr",
+        "$a == $a ==>> true",
+        "--callsite-qreplace",
+        "old::check=new::check",
+    ];
+    assert_eq!(
+        scratch.rewrite("a.rs", &args),
+        "use std::collections::HashMap;
+fn f(a: Option<u8>, b: bool, c: bool) {
+    let m = HashMap::with_capacity(16);
+    let y = new::check(b || c).unwrap_or_default();
+    let z = (b || c).clone();
+    if true {}
+}
+"
+    );
+}